@@ -0,0 +1,280 @@
+//! Compact form of the code used by the interpreter loop
+//!
+//! Labels are resolved to code addresses and string operands are
+//! replaced by their index in a constant pool, so that every
+//! instruction is a small `Copy` value that can be dispatched
+//! without allocating or hashing.
+//!
+//! Common sequences of instructions can then be fused into
+//! superinstructions, which stand for the whole sequence when running
+//! without the debugger.
+
+use std::collections::HashMap;
+
+use errors::*;
+use instructions::Instruction;
+use program::Program;
+
+/// A pre-resolved `vm` instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Pushi(i32),
+    Pushn(i32),
+    Pushg(usize),
+    /// Index in the constant pool
+    Pushs(usize),
+    /// Code address
+    Pusha(usize),
+    Pushgp,
+    Call,
+    Return,
+    Start,
+    Nop,
+    Stop,
+    Loadn,
+    Writei,
+    Writes,
+    Read,
+    Atoi,
+    Padd,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Storeg(usize),
+    Storen,
+    Equal,
+    Inf,
+    Infeq,
+    Sup,
+    Supeq,
+    /// Code address
+    Jump(usize),
+    /// Code address
+    Jz(usize),
+    /// Index in the constant pool
+    Err(usize),
+
+    // Superinstructions
+
+    /// `pushgp; pushi k; padd`
+    GlobalAddr(i32),
+    /// `pushgp; pushi k; padd; pushi 0; loadn`
+    LoadGlobal(i32),
+    /// `pushgp; pushi k; padd; pushi 0; pushi v; storen`
+    StoreGlobalI(i32, i32),
+    /// `pushgp; pushi k; padd; pushi 0; pushg n; storen`
+    StoreGlobalG(i32, usize),
+}
+
+impl Op {
+    /// Number of instructions the operation stands for
+    pub fn width(&self) -> usize {
+        match *self {
+            Op::GlobalAddr(..) => 3,
+            Op::LoadGlobal(..) => 5,
+            Op::StoreGlobalI(..) | Op::StoreGlobalG(..) => 6,
+            _ => 1,
+        }
+    }
+
+    /// Number of values the operation takes off the top of the stack
+    pub fn pops(&self) -> usize {
+        match *self {
+            Op::Storen => 3,
+            Op::Loadn | Op::Padd | Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod => 2,
+            Op::Equal | Op::Inf | Op::Infeq | Op::Sup | Op::Supeq => 2,
+            Op::Call | Op::Writei | Op::Writes | Op::Atoi | Op::Storeg(..) | Op::Jz(..) => 1,
+            Op::Pushi(..) | Op::Pushn(..) | Op::Pushg(..) | Op::Pushs(..) | Op::Pusha(..) => 0,
+            Op::Pushgp | Op::Return | Op::Start | Op::Nop | Op::Stop | Op::Read => 0,
+            Op::Jump(..) | Op::Err(..) => 0,
+            // they push everything they pop
+            Op::GlobalAddr(..) | Op::LoadGlobal(..) => 0,
+            Op::StoreGlobalI(..) | Op::StoreGlobalG(..) => 0,
+        }
+    }
+
+    /// How much deeper than at the start the stack gets while running
+    /// the instructions of a superinstruction
+    pub fn peak(&self) -> usize {
+        match *self {
+            Op::GlobalAddr(..) | Op::LoadGlobal(..) => 2,
+            Op::StoreGlobalI(..) | Op::StoreGlobalG(..) => 3,
+            _ => 0,
+        }
+    }
+}
+
+/// A program ready to be run
+#[derive(Debug, Clone, Default)]
+pub struct Bytecode {
+    /// One operation for each instruction of the source code
+    pub ops: Vec<Op>,
+    /// The distinct string operands, in order of appearance
+    pub constants: Vec<String>,
+}
+
+/// Interns the string operands of a program
+#[derive(Default)]
+struct Pool {
+    constants: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl Pool {
+    fn intern(&mut self, s: &str) -> usize {
+        if let Some(&i) = self.index.get(s) {
+            return i;
+        }
+        let i = self.constants.len();
+        self.constants.push(s.to_string());
+        self.index.insert(s.to_string(), i);
+        i
+    }
+}
+
+/// Resolve the labels and strings of a parsed program
+pub fn compile(program: &Program) -> Result<Bytecode> {
+    let mut pool = Pool::default();
+
+    let mut ops = Vec::with_capacity(program.code.len());
+    for (i, instr) in program.code.iter().enumerate() {
+        let label = |name: &str| -> Result<usize> {
+            match program.labels.get(name) {
+                Some(&addr) => Ok(addr),
+                None => bail!(ErrorKind::Syntax(
+                    program.spans.get(i).cloned().unwrap_or_default(),
+                    format!("Undefined label '{}'", name)
+                )),
+            }
+        };
+
+        ops.push(match *instr {
+            Instruction::Pushi(val) => Op::Pushi(val),
+            Instruction::Pushn(val) => Op::Pushn(val),
+            Instruction::Pushg(val) => Op::Pushg(val),
+            Instruction::Pushs(ref val) => Op::Pushs(pool.intern(val)),
+            Instruction::Pusha(ref val) => Op::Pusha(label(val)?),
+            Instruction::Pushgp => Op::Pushgp,
+            Instruction::Call => Op::Call,
+            Instruction::Return => Op::Return,
+            Instruction::Start => Op::Start,
+            Instruction::Nop => Op::Nop,
+            Instruction::Stop => Op::Stop,
+            Instruction::Loadn => Op::Loadn,
+            Instruction::Writei => Op::Writei,
+            Instruction::Writes => Op::Writes,
+            Instruction::Read => Op::Read,
+            Instruction::Atoi => Op::Atoi,
+            Instruction::Padd => Op::Padd,
+            Instruction::Add => Op::Add,
+            Instruction::Sub => Op::Sub,
+            Instruction::Mul => Op::Mul,
+            Instruction::Div => Op::Div,
+            Instruction::Mod => Op::Mod,
+            Instruction::Storeg(val) => Op::Storeg(val),
+            Instruction::Storen => Op::Storen,
+            Instruction::Equal => Op::Equal,
+            Instruction::Inf => Op::Inf,
+            Instruction::Infeq => Op::Infeq,
+            Instruction::Sup => Op::Sup,
+            Instruction::Supeq => Op::Supeq,
+            Instruction::Jump(ref val) => Op::Jump(label(val)?),
+            Instruction::Jz(ref val) => Op::Jz(label(val)?),
+            Instruction::Err(ref val) => Op::Err(pool.intern(val)),
+        });
+    }
+
+    Ok(Bytecode {
+        ops,
+        constants: pool.constants,
+    })
+}
+
+/// Superinstructions for the sequences that start at each code address
+///
+/// Every address keeps its own operation, so that jumping into the
+/// middle of a fused sequence runs the original instructions.
+pub fn fuse(ops: &[Op]) -> Vec<Op> {
+    let mut fast = ops.to_vec();
+    for (i, op) in fast.iter_mut().enumerate() {
+        *op = match ops[i..] {
+            [Op::Pushgp, Op::Pushi(k), Op::Padd, Op::Pushi(0), Op::Loadn, ..] => Op::LoadGlobal(k),
+            [Op::Pushgp, Op::Pushi(k), Op::Padd, Op::Pushi(0), Op::Pushi(v), Op::Storen, ..] => {
+                Op::StoreGlobalI(k, v)
+            }
+            [Op::Pushgp, Op::Pushi(k), Op::Padd, Op::Pushi(0), Op::Pushg(n), Op::Storen, ..] => {
+                Op::StoreGlobalG(k, n)
+            }
+            [Op::Pushgp, Op::Pushi(k), Op::Padd, ..] => Op::GlobalAddr(k),
+            _ => continue,
+        };
+    }
+    fast
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, fuse, Op};
+    use errors::ErrorKind;
+    use parser::parse;
+
+    #[test]
+    fn compile_labels() {
+        let program = parse("start\nloop: pusha f\njz loop\njump end\nf: return\nend: stop\n").unwrap();
+        let ops = compile(&program).unwrap().ops;
+        assert_eq!(ops, [Op::Start, Op::Pusha(4), Op::Jz(1), Op::Jump(5), Op::Return, Op::Stop]);
+    }
+
+    #[test]
+    fn compile_constants() {
+        let program = parse("pushs \"a\"\npushs \"b\"\npushs \"a\"\nerr \"b\"\nerr \"c\"\n").unwrap();
+        let bytecode = compile(&program).unwrap();
+        assert_eq!(bytecode.ops, [Op::Pushs(0), Op::Pushs(1), Op::Pushs(0), Op::Err(1), Op::Err(2)]);
+        assert_eq!(bytecode.constants, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn undefined_label() {
+        let program = parse("start\njump nowhere\n").unwrap();
+        let e = compile(&program).err().unwrap();
+        match *e.kind() {
+            ErrorKind::Syntax(ref span, ref s) => {
+                assert_eq!(s, "Undefined label 'nowhere'");
+                assert_eq!(span.to_string(), "line(2), col(1)");
+            }
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn fuse_global_access() {
+        let ops = [
+            Op::Pushgp,
+            Op::Pushi(3),
+            Op::Padd,
+            Op::Pushi(0),
+            Op::Loadn,
+            Op::Pushgp,
+            Op::Pushi(1),
+            Op::Padd,
+            Op::Pushi(0),
+            Op::Pushg(2),
+            Op::Storen,
+        ];
+        let fast = fuse(&ops);
+        assert_eq!(fast[0], Op::LoadGlobal(3));
+        assert_eq!(fast[5], Op::StoreGlobalG(1, 2));
+        // every other address keeps its own instruction
+        for i in (1..5).chain(6..ops.len()) {
+            assert_eq!(fast[i], ops[i]);
+        }
+    }
+
+    #[test]
+    fn fuse_address_only() {
+        let ops = [Op::Pushgp, Op::Pushi(1), Op::Padd, Op::Pushg(0), Op::Add];
+        assert_eq!(fuse(&ops)[0], Op::GlobalAddr(1));
+    }
+}
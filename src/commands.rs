@@ -1,7 +1,9 @@
 //! debug CLI command parsing
 
 use errors::*;
+use expr::Expr;
 
+use std::fmt;
 use std::str::FromStr;
 
 /// The status of the debug `vm` CLI
@@ -13,12 +15,89 @@ pub enum Status {
     Exit,
 }
 
+/// A code address given by the user
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Pc(usize),
+    Label(String),
+}
+
+impl FromStr for Location {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Location> {
+        match s.parse() {
+            Ok(pc) => Ok(Location::Pc(pc)),
+            Err(_) if s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => {
+                Ok(Location::Label(s.to_string()))
+            }
+            Err(_) => bail!("'{}' is not a label or a code address", s),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::Pc(pc) => write!(f, "{}", pc),
+            Location::Label(ref label) => write!(f, "{}", label),
+        }
+    }
+}
+
+/// The full names of the commands, for completion
+pub const NAMES: &[&str] = &[
+    "back", "backtrace", "break", "code", "condition", "continue", "delete", "disable", "down",
+    "enable", "finish", "frame", "globals", "goto-step", "help", "info", "labels", "next", "over",
+    "pop", "print", "push", "quit", "registers", "restart", "reverse-continue", "reverse-step",
+    "run", "rwatch", "save", "set", "source", "stack", "step", "strings", "until", "up", "watch",
+    "where", "x",
+];
+
 /// Possible commands for the debug CLI
 #[derive(Debug, Clone)]
 pub enum Command {
     Run,
     Step(usize),
+    /// Step over a `call`, running the function until it returns
+    Over,
+    Finish,
+    Until(Location),
     Next(usize),
+    /// Undo a number of instructions
+    Back(u64),
+    ReverseContinue,
+    GotoStep(u64),
+    /// Stop at a location, when the condition holds if there is one
+    Break(Location, Option<Expr>),
+    /// Stop after a write to a stack slot
+    Watch(Expr, Option<Expr>),
+    /// Stop after a read of a stack slot
+    Rwatch(Expr, Option<Expr>),
+    Condition(usize, Option<Expr>),
+    Print(Expr),
+    /// Examine a number of stack slots from an address
+    Examine(Expr, usize),
+    PrintStrings,
+    PrintGlobals,
+    SetPc(Location),
+    SetFp(Expr),
+    /// Store a value in a stack slot
+    SetSlot(Expr, Expr),
+    Push(Expr),
+    Pop,
+    Delete(Option<usize>),
+    Enable(Option<usize>),
+    Disable(Option<usize>),
+    InfoBreakpoints,
+    Backtrace,
+    /// Select a frame, or show the selected one
+    Frame(Option<usize>),
+    Up(usize),
+    Down(usize),
+    Save,
+    Restart,
+    /// Run the commands in a file
+    Source(String),
     PrintRegisters,
     PrintStack,
     PrintCode,
@@ -29,25 +108,107 @@ pub enum Command {
 }
 
 impl Command {
+    /// Whether an empty line runs the command again
+    pub fn repeats(&self) -> bool {
+        matches!(*self, Command::Step(_) | Command::Over | Command::Next(_))
+    }
+
     /// Print Usage description
     pub fn help() {
         let help = [
-            ("r, run", "Continue the execution"),
+            (
+                "r, run, continue",
+                "Continue the execution until a breakpoint or the end",
+            ),
             (
                 "s, step [NUMBER]",
                 "Step by NUMBER instructions. NUMBER defaults to 1",
             ),
+            ("o, over", "Step over a call, stopping after it returns"),
+            ("fin, finish", "Run until the current function returns"),
+            (
+                "u, until LOCATION",
+                "Run until LOCATION or the current function returns",
+            ),
             (
                 "n, next [NUMBER]",
                 "Show NUMBER instructions. NUMBER defaults to 1",
             ),
+            (
+                "back [NUMBER]",
+                "Go back by NUMBER instructions. NUMBER defaults to 1",
+            ),
+            (
+                "rc, reverse-continue",
+                "Run backwards until a breakpoint or the start",
+            ),
+            (
+                "goto-step NUMBER",
+                "Go to the state before instruction NUMBER runs",
+            ),
+            (
+                "b, break LOCATION",
+                "Stop before the instruction at LOCATION, a label or a pc",
+            ),
+            (
+                "break LOCATION if EXPR",
+                "Stop at LOCATION only when EXPR is not 0",
+            ),
+            (
+                "watch SLOT [if EXPR]",
+                "Stop after a write to SLOT: gp[n], fp[n] or stack[i]",
+            ),
+            ("rwatch SLOT [if EXPR]", "Stop after a read of SLOT"),
+            (
+                "condition ID [EXPR]",
+                "Set the condition of breakpoint ID, or remove it",
+            ),
+            (
+                "p, print EXPR",
+                "Print the value of EXPR, e.g. 'print gp[0] + 1'",
+            ),
+            (
+                "d, delete [ID]",
+                "Delete breakpoint ID. Deletes all without ID",
+            ),
+            ("disable [ID]", "Disable breakpoint ID, or all of them"),
+            ("enable [ID]", "Enable breakpoint ID, or all of them"),
+            ("i b, info breakpoints", "List the breakpoints"),
+            (
+                "save",
+                "Save the breakpoints, to be set again in the next session",
+            ),
+            (
+                "restart",
+                "Run the program again from the start, keeping the breakpoints",
+            ),
+            ("bt, backtrace", "List the calls in progress, the innermost first"),
+            (
+                "f, frame [N]",
+                "Select frame N for 'stack' and 'fp[n]', or show the selected one",
+            ),
+            ("up [N], down [N]", "Select the frame N calls out or in"),
             (
                 "reg, registers",
                 "Print the current value for the registers",
             ),
             ("st, stack", "Print the current state of the stack"),
+            (
+                "x ADDR [COUNT]",
+                "Print COUNT stack slots from ADDR. COUNT defaults to 1",
+            ),
+            ("strings", "Print the string area, with the address of each"),
+            ("globals", "Print the global variables, gp[0] to the first frame"),
             ("c, code", "Print the code that is beeing run"),
             ("l, labels", "Print all labels found in the code"),
+            ("set pc LOCATION", "Continue the execution at LOCATION"),
+            ("set fp EXPR", "Move the frame pointer to slot EXPR"),
+            (
+                "set SLOT = EXPR",
+                "Store EXPR in SLOT: gp[n], fp[n] or stack[i]",
+            ),
+            ("push EXPR, pop", "Push a value on the stack, or pop the top one"),
+            ("source FILE", "Run the commands in FILE, where '#' starts a comment"),
             ("h, help", "Print this message"),
             ("q, quit", "Exit from the debugger"),
         ];
@@ -55,29 +216,215 @@ impl Command {
         println!();
         println!("COMMANDS:");
         for &(cmd, msg) in &help {
-            println!("\t{:20}{}", cmd, msg);
+            println!("\t{:24}{}", cmd, msg);
         }
         println!()
     }
 }
 
+/// Parse an optional breakpoint id
+fn id(arg: Option<&str>) -> Result<Option<usize>> {
+    match arg {
+        Some(n) => Ok(Some(n.parse().chain_err(|| "Not a valid argument")?)),
+        None => Ok(None),
+    }
+}
+
+/// Split off the first word of `s`, and the rest without leading spaces
+fn word(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+/// Parse `LOCATION [if EXPR]`
+fn breakpoint(args: &str) -> Result<Command> {
+    let (location, rest) = word(args);
+    if location.is_empty() {
+        bail!("Missing location. See 'help' for usage");
+    }
+    let condition = match word(rest) {
+        ("", _) => None,
+        ("if", cond) if !cond.is_empty() => Some(Expr::parse(cond)?),
+        _ => bail!("Invalid argument. Expected 'if EXPR' after the location"),
+    };
+    Ok(Command::Break(location.parse()?, condition))
+}
+
+/// The characters of `s` outside of string literals, with their position
+fn unquoted<'a>(s: &'a str) -> impl Iterator<Item = (usize, char)> + 'a {
+    let mut quoted = false;
+    let mut escaped = false;
+    s.char_indices().filter(move |&(_, c)| {
+        if quoted {
+            match c {
+                '\\' => escaped = !escaped,
+                '"' if !escaped => quoted = false,
+                _ => escaped = false,
+            }
+            false
+        } else {
+            quoted = c == '"';
+            !quoted
+        }
+    })
+}
+
+/// Split `EXPR [if EXPR]` at the `if` word outside of string literals
+fn split_if(s: &str) -> (&str, Option<&str>) {
+    for (i, c) in unquoted(s) {
+        if c.is_whitespace() {
+            if let ("if", cond) = word(&s[i..]) {
+                return (&s[..i], Some(cond));
+            }
+        }
+    }
+    (s, None)
+}
+
+/// Drop the comment at the end of a line, from a `#` outside of string literals
+fn strip_comment(s: &str) -> &str {
+    match unquoted(s).find(|&(_, c)| c == '#') {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
+/// Parse `SLOT [if EXPR]`
+fn watchpoint(args: &str) -> Result<(Expr, Option<Expr>)> {
+    let (slot, condition) = split_if(args);
+    if slot.trim().is_empty() {
+        bail!("Missing slot. See 'help' for usage");
+    }
+    let slot = Expr::parse(slot)?;
+    if let Expr::Slot(..) = slot {
+    } else {
+        bail!("Only stack slots can be watched: gp[n], fp[n] or stack[i]");
+    }
+    let condition = match condition {
+        Some("") => bail!("Missing expression after 'if'"),
+        Some(cond) => Some(Expr::parse(cond)?),
+        None => None,
+    };
+    Ok((slot, condition))
+}
+
+/// Parse `EXPR [COUNT]`, where a last word that is a number is the count
+/// if what comes before it is an expression
+fn examine(args: &str) -> Result<Command> {
+    if args.is_empty() {
+        bail!("Missing address. See 'help' for usage");
+    }
+    if let Some(i) = args.rfind(char::is_whitespace) {
+        if let (Ok(count), Ok(addr)) = (args[i..].trim().parse(), Expr::parse(&args[..i])) {
+            return Ok(Command::Examine(addr, count));
+        }
+    }
+    Ok(Command::Examine(Expr::parse(args)?, 1))
+}
+
+/// Parse `pc LOCATION`, `fp EXPR` or `SLOT = EXPR`
+fn set(args: &str) -> Result<Command> {
+    match word(args) {
+        ("pc", location) if !location.is_empty() => return Ok(Command::SetPc(location.parse()?)),
+        ("fp", value) if !value.is_empty() => return Ok(Command::SetFp(Expr::parse(value)?)),
+        _ => {}
+    }
+    // the first `=` that is not part of a comparison
+    let bytes = args.as_bytes();
+    let eq = (0..bytes.len()).find(|&i| {
+        bytes[i] == b'='
+            && (i == 0 || !b"=!<>".contains(&bytes[i - 1]))
+            && bytes.get(i + 1) != Some(&b'=')
+    });
+    let eq = eq.ok_or("Expected 'set pc LOCATION', 'set fp EXPR' or 'set SLOT = EXPR'")?;
+    let slot = Expr::parse(&args[..eq])?;
+    if let Expr::Slot(..) = slot {
+    } else {
+        bail!("Only stack slots can be set: gp[n], fp[n] or stack[i]");
+    }
+    Ok(Command::SetSlot(slot, Expr::parse(&args[eq + 1..])?))
+}
+
+/// Parse `ID [EXPR]`
+fn condition(args: &str) -> Result<Command> {
+    let (n, rest) = word(args);
+    let n = n.parse().chain_err(|| "Not a valid argument")?;
+    let condition = match rest {
+        "" => None,
+        cond => Some(Expr::parse(cond)?),
+    };
+    Ok(Command::Condition(n, condition))
+}
+
 impl FromStr for Command {
     type Err = Error;
     fn from_str(s: &str) -> Result<Command> {
+        let s = strip_comment(s);
+        // commands that take an expression use the rest of the line
+        let (cmd, rest) = word(s);
+        match cmd.to_lowercase().as_ref() {
+            "b" | "break" => return breakpoint(rest),
+            "condition" => return condition(rest),
+            "watch" => return watchpoint(rest).map(|(slot, cond)| Command::Watch(slot, cond)),
+            "rwatch" => return watchpoint(rest).map(|(slot, cond)| Command::Rwatch(slot, cond)),
+            "p" | "print" if rest.is_empty() => bail!("Missing expression. See 'help' for usage"),
+            "p" | "print" => return Ok(Command::Print(Expr::parse(rest)?)),
+            "x" => return examine(rest),
+            "set" => return set(rest),
+            "push" if rest.is_empty() => bail!("Missing value. See 'help' for usage"),
+            "push" => return Ok(Command::Push(Expr::parse(rest)?)),
+            "source" if rest.is_empty() => bail!("Missing file. See 'help' for usage"),
+            "source" => return Ok(Command::Source(rest.to_string())),
+            _ => {}
+        }
+
         let mut args = s.split_whitespace();
         if let Some(cmd) = args.next() {
             let res = match cmd.to_lowercase().as_ref() {
                 "reg" | "registers" => Ok(Command::PrintRegisters),
                 "st" | "stack" => Ok(Command::PrintStack),
+                "strings" => Ok(Command::PrintStrings),
+                "globals" => Ok(Command::PrintGlobals),
+                "pop" => Ok(Command::Pop),
                 "l" | "labels" => Ok(Command::PrintLabels),
                 "c" | "code" => Ok(Command::PrintCode),
                 "h" | "help" => Ok(Command::Help),
                 "q" | "quit" => Ok(Command::Quit),
-                "r" | "run" => Ok(Command::Run),
+                "r" | "run" | "continue" | "cont" => Ok(Command::Run),
+                "d" | "delete" => Ok(Command::Delete(id(args.next())?)),
+                "enable" => Ok(Command::Enable(id(args.next())?)),
+                "disable" => Ok(Command::Disable(id(args.next())?)),
+                "i" | "info" => match args.next() {
+                    Some("b") | Some("break") | Some("breakpoints") => Ok(Command::InfoBreakpoints),
+                    _ => Err("Unknown info. Try 'info breakpoints'".into()),
+                },
+                "bt" | "backtrace" | "where" => Ok(Command::Backtrace),
+                "f" | "frame" => Ok(Command::Frame(id(args.next())?)),
+                "up" => Ok(Command::Up(id(args.next())?.unwrap_or(1))),
+                "down" => Ok(Command::Down(id(args.next())?.unwrap_or(1))),
+                "save" => Ok(Command::Save),
+                "restart" => Ok(Command::Restart),
                 "n" | "next" => Ok(Command::Next(args.next()
                     .unwrap_or("1")
                     .parse()
                     .chain_err(|| "Not a valid argument")?)),
+                "back" | "reverse-step" => Ok(Command::Back(args.next()
+                    .unwrap_or("1")
+                    .parse()
+                    .chain_err(|| "Not a valid argument")?)),
+                "rc" | "reverse-continue" => Ok(Command::ReverseContinue),
+                "o" | "over" => Ok(Command::Over),
+                "fin" | "finish" => Ok(Command::Finish),
+                "u" | "until" => Ok(Command::Until(args.next()
+                    .ok_or("Missing location. See 'help' for usage")?
+                    .parse()?)),
+                "goto-step" => Ok(Command::GotoStep(args.next()
+                    .ok_or("Missing step number. See 'help' for usage")?
+                    .parse()
+                    .chain_err(|| "Not a valid argument")?)),
                 "s" | "step" => Ok(Command::Step(args.next()
                     .unwrap_or("1")
                     .parse()
@@ -94,3 +441,123 @@ impl FromStr for Command {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Location};
+    use expr::Expr;
+
+    #[test]
+    fn location() {
+        assert_eq!("12".parse::<Location>().unwrap(), Location::Pc(12));
+        assert_eq!("loop".parse::<Location>().unwrap(), Location::Label("loop".to_string()));
+        assert!("-1".parse::<Location>().is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        match "b cycle0".parse() {
+            Ok(Command::Break(Location::Label(ref l), None)) if l == "cycle0" => {}
+            c => panic!("{:?}", c),
+        }
+        match "break 3 if gp[0] == 2 && sp > 1".parse() {
+            Ok(Command::Break(Location::Pc(3), Some(ref e))) => {
+                assert_eq!(*e, Expr::parse("gp[0] == 2 && sp > 1").unwrap())
+            }
+            c => panic!("{:?}", c),
+        }
+        assert!(matches!("condition 2".parse(), Ok(Command::Condition(2, None))));
+        match "watch gp[1] if gp[1] == \" if \"".parse() {
+            Ok(Command::Watch(ref slot, Some(ref cond))) => {
+                assert_eq!(*slot, Expr::parse("gp[1]").unwrap());
+                assert_eq!(*cond, Expr::parse("gp[1] == \" if \"").unwrap());
+            }
+            c => panic!("{:?}", c),
+        }
+        assert!(matches!("rwatch stack[-1]".parse(), Ok(Command::Rwatch(_, None))));
+        assert!("watch sp".parse::<Command>().is_err());
+        assert!("watch gp[0] if".parse::<Command>().is_err());
+        assert!(matches!("p stack[-1]".parse(), Ok(Command::Print(_))));
+        assert!("break 3 when 1".parse::<Command>().is_err());
+        assert!("break 3 if".parse::<Command>().is_err());
+        assert!("print".parse::<Command>().is_err());
+        assert!(matches!("delete".parse(), Ok(Command::Delete(None))));
+        assert!(matches!("disable 2".parse(), Ok(Command::Disable(Some(2)))));
+        assert!(matches!("info breakpoints".parse(), Ok(Command::InfoBreakpoints)));
+        assert!(matches!("continue".parse(), Ok(Command::Run)));
+        assert!("break".parse::<Command>().is_err());
+        assert!("enable x".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn reverse() {
+        assert!(matches!("back".parse(), Ok(Command::Back(1))));
+        assert!(matches!("reverse-step 5".parse(), Ok(Command::Back(5))));
+        assert!(matches!("rc".parse(), Ok(Command::ReverseContinue)));
+        assert!(matches!("goto-step 30".parse(), Ok(Command::GotoStep(30))));
+        assert!("goto-step".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn stepping() {
+        assert!(matches!("over".parse(), Ok(Command::Over)));
+        assert!(matches!("fin".parse(), Ok(Command::Finish)));
+        assert!(matches!("until 4".parse(), Ok(Command::Until(Location::Pc(4)))));
+        assert!("until".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn frames() {
+        assert!(matches!("bt".parse(), Ok(Command::Backtrace)));
+        assert!(matches!("frame 2".parse(), Ok(Command::Frame(Some(2)))));
+        assert!(matches!("up".parse(), Ok(Command::Up(1))));
+        assert!(matches!("down 3".parse(), Ok(Command::Down(3))));
+    }
+
+    #[test]
+    fn examine() {
+        match "x gp + 2 4".parse() {
+            Ok(Command::Examine(ref e, 4)) => assert_eq!(*e, Expr::parse("gp + 2").unwrap()),
+            c => panic!("{:?}", c),
+        }
+        match "x sp - 3".parse() {
+            Ok(Command::Examine(ref e, 1)) => assert_eq!(*e, Expr::parse("sp - 3").unwrap()),
+            c => panic!("{:?}", c),
+        }
+        assert!("x".parse::<Command>().is_err());
+        assert!(matches!("strings".parse(), Ok(Command::PrintStrings)));
+        assert!(matches!("globals".parse(), Ok(Command::PrintGlobals)));
+    }
+
+    #[test]
+    fn set() {
+        match "set gp[1] = gp[0] == 2".parse() {
+            Ok(Command::SetSlot(ref slot, ref v)) => {
+                assert_eq!(*slot, Expr::parse("gp[1]").unwrap());
+                assert_eq!(*v, Expr::parse("gp[0] == 2").unwrap());
+            }
+            c => panic!("{:?}", c),
+        }
+        assert!(matches!("set stack[sp-1]=3a".parse(), Ok(Command::SetSlot(..))));
+        assert!(matches!("set pc loop".parse(), Ok(Command::SetPc(Location::Label(_)))));
+        assert!(matches!("set fp sp".parse(), Ok(Command::SetFp(_))));
+        assert!(matches!("push -4".parse(), Ok(Command::Push(_))));
+        assert!(matches!("pop".parse(), Ok(Command::Pop)));
+        assert!("set sp = 1".parse::<Command>().is_err());
+        assert!("set gp[0] == 1".parse::<Command>().is_err());
+        assert!("push".parse::<Command>().is_err());
+        assert!("pop 1".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn comments() {
+        assert!(matches!("# set a breakpoint".parse(), Ok(Command::Empty)));
+        assert!(matches!("step 3 # into the loop".parse(), Ok(Command::Step(3))));
+        match "print \"#\" == str(gp[0]) # a string".parse() {
+            Ok(Command::Print(ref e)) => assert_eq!(*e, Expr::parse("\"#\" == str(gp[0])").unwrap()),
+            c => panic!("{:?}", c),
+        }
+        assert!(matches!("source cmds.txt".parse(), Ok(Command::Source(ref f)) if f == "cmds.txt"));
+        assert!("source # no file".parse::<Command>().is_err());
+    }
+}
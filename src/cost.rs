@@ -0,0 +1,146 @@
+//! Abstract cycle cost of each instruction
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use errors::*;
+use instructions::Instruction;
+
+/// Cost of every instruction when no cost file is given
+const DEFAULT_COSTS: &[(&str, u64)] = &[
+    ("pushi", 1),
+    ("pushn", 1),
+    ("pushg", 2),
+    ("pushs", 2),
+    ("pusha", 1),
+    ("pushgp", 1),
+    ("call", 5),
+    ("return", 5),
+    ("start", 1),
+    ("nop", 1),
+    ("stop", 1),
+    ("loadn", 2),
+    ("writei", 10),
+    ("writes", 10),
+    ("read", 10),
+    ("atoi", 5),
+    ("padd", 1),
+    ("add", 1),
+    ("sub", 1),
+    ("mul", 3),
+    ("div", 8),
+    ("mod", 8),
+    ("storeg", 2),
+    ("storen", 2),
+    ("equal", 1),
+    ("inf", 1),
+    ("infeq", 1),
+    ("sup", 1),
+    ("supeq", 1),
+    ("jump", 1),
+    ("jz", 2),
+    ("err", 1),
+];
+
+/// The cost of each instruction, by mnemonic
+#[derive(Debug, Clone)]
+pub struct CostTable {
+    costs: HashMap<&'static str, u64>,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable {
+            costs: DEFAULT_COSTS.iter().cloned().collect(),
+        }
+    }
+}
+
+impl CostTable {
+    /// The cost of executing an instruction
+    pub fn get(&self, instr: &Instruction) -> u64 {
+        self.costs.get(instr.name()).cloned().unwrap_or(1)
+    }
+
+    /// Read a cost file, keeping the defaults for instructions it does not mention
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut buffer = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut buffer))
+            .chain_err(|| format!("Unable to Read file '{}'", path.as_ref().display()))?;
+
+        buffer
+            .parse()
+            .chain_err(|| format!("Invalid cost file '{}'", path.as_ref().display()))
+    }
+
+    /// Update the table from lines of `<instruction> = <cost>`, with `//` comments
+    pub fn parse(&mut self, input: &str) -> Result<()> {
+        for (n, line) in input.lines().enumerate() {
+            let line = line.split("//").next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let (name, cost) = match (parts.next(), parts.next()) {
+                (Some(name), Some(cost)) => (name.trim().to_lowercase(), cost.trim()),
+                _ => bail!("line({}) => expected '<instruction> = <cost>'", n + 1),
+            };
+
+            let key = match DEFAULT_COSTS.iter().find(|&&(k, _)| k == name) {
+                Some(&(k, _)) => k,
+                None => bail!("line({}) => unknown instruction '{}'", n + 1, name),
+            };
+            let cost = cost
+                .parse()
+                .chain_err(|| format!("line({}) => '{}' is not a valid cost", n + 1, cost))?;
+
+            self.costs.insert(key, cost);
+        }
+        Ok(())
+    }
+}
+
+impl ::std::str::FromStr for CostTable {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut table = CostTable::default();
+        table.parse(s)?;
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CostTable;
+    use instructions::Instruction as ins;
+
+    #[test]
+    fn defaults() {
+        let table = CostTable::default();
+        assert!(table.get(&ins::Call) > table.get(&ins::Pushi(0)));
+    }
+
+    #[test]
+    fn overrides() {
+        let table: CostTable = "// cheap calls\nCALL = 1\n\npushi=4 // expensive".parse().unwrap();
+        assert_eq!(table.get(&ins::Call), 1);
+        assert_eq!(table.get(&ins::Pushi(0)), 4);
+        assert_eq!(table.get(&ins::Mul), CostTable::default().get(&ins::Mul));
+    }
+
+    #[test]
+    fn unknown_instruction() {
+        let e = "pushi = 2\npushx = 1".parse::<CostTable>().unwrap_err();
+        assert_eq!(e.to_string(), "line(2) => unknown instruction 'pushx'");
+    }
+
+    #[test]
+    fn invalid_cost() {
+        let e = "// costs\npushi = -1".parse::<CostTable>().unwrap_err();
+        assert_eq!(e.to_string(), "line(2) => '-1' is not a valid cost");
+    }
+}
@@ -0,0 +1,1225 @@
+//! Interactive debugger
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use errors;
+use errors::*;
+
+use commands::{Command, Location, Status};
+use expr::{Expr, Value};
+use history::History;
+use instructions::{escape, Instruction};
+use prompt::Prompt;
+use vm::{Access, Machine, Operand};
+
+/// How deep command files can run one another, to stop one that runs itself
+const MAX_SOURCING: usize = 16;
+
+/// What makes a breakpoint stop the execution
+#[derive(Debug, Clone)]
+enum Trigger {
+    /// Reaching a code address, before the instruction there runs
+    Code(Location),
+    /// A write to a stack slot, after the instruction that did it
+    Write(Expr),
+    /// A read of a stack slot, after the instruction that did it
+    Read(Expr),
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trigger::Code(ref location) => write!(f, "break {}", location),
+            Trigger::Write(ref slot) => write!(f, "watch {}", slot),
+            Trigger::Read(ref slot) => write!(f, "rwatch {}", slot),
+        }
+    }
+}
+
+/// Why running the program stopped
+#[derive(Debug, Clone, Copy)]
+enum Stop {
+    Exit,
+    Breakpoint,
+    /// What the command ran for was reached
+    Done,
+}
+
+impl Stop {
+    fn status(self) -> Status {
+        match self {
+            Stop::Exit => Status::Exit,
+            Stop::Breakpoint | Stop::Done => Status::Success,
+        }
+    }
+}
+
+/// A breakpoint or a watchpoint, which share their ids
+#[derive(Debug, Clone)]
+struct Breakpoint {
+    id: usize,
+    /// As given by the user
+    trigger: Trigger,
+    /// The code address or stack slot the trigger resolved to
+    address: usize,
+    /// Only stop when this is true
+    condition: Option<Expr>,
+    enabled: bool,
+    /// Number of times the execution stopped here
+    hits: u64,
+}
+
+impl Breakpoint {
+    /// The debugger command that sets this breakpoint again
+    fn command(&self) -> String {
+        match self.condition {
+            Some(ref cond) => format!("{} if {}", self.trigger, cond),
+            None => self.trigger.to_string(),
+        }
+    }
+
+    fn is_code(&self, pc: usize) -> bool {
+        match self.trigger {
+            Trigger::Code(..) => self.enabled && self.address == pc,
+            _ => false,
+        }
+    }
+}
+
+/// The debug CLI, controlling a loaded `vm`
+pub struct Debugger<'a> {
+    machine: &'a mut Machine,
+    /// The program being debugged, loaded again on restart
+    path: PathBuf,
+    status: Status,
+    /// What was run, to go back to
+    history: History,
+    breakpoints: Vec<Breakpoint>,
+    /// Ids are never reused, so they stay valid after a delete
+    next_id: usize,
+    /// The frame `stack` and `fp[n]` refer to, counted from the innermost
+    frame: usize,
+    /// The step at which an error ended the program, and its exit code
+    failure: Option<(u64, i32)>,
+    /// Number of command files being run, one inside the other
+    sourcing: usize,
+    /// Set by `quit`, to end the session
+    quit: bool,
+    /// The step and pc the program last stopped at, where a breakpoint is
+    /// not hit again when it resumes
+    stopped_at: Option<(u64, usize)>,
+}
+
+/// A function call in progress
+struct Frame {
+    /// Where the frame is running, which is the return address for callers
+    pc: usize,
+    fp: usize,
+    /// The entry label of the function, `None` for the top level
+    function: Option<String>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(machine: &'a mut Machine, path: &Path) -> Self {
+        Debugger {
+            history: History::new(machine),
+            machine,
+            path: path.to_path_buf(),
+            status: Status::Success,
+            breakpoints: Vec::new(),
+            next_id: 1,
+            frame: 0,
+            failure: None,
+            sourcing: 0,
+            quit: false,
+            stopped_at: None,
+        }
+    }
+
+    /// The file the breakpoints of the program are saved to
+    fn sidecar(&self) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(".breakpoints");
+        PathBuf::from(name)
+    }
+
+    /// Set the breakpoints saved in a previous session
+    fn load_breakpoints(&mut self) -> Result<()> {
+        let path = self.sidecar();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).chain_err(|| format!("Unable to Read file '{}'", path.display())),
+        };
+        println!("Reading breakpoints from '{}'", path.display());
+        for line in text.lines() {
+            let cmd = line.parse()
+                .chain_err(|| format!("Invalid command '{}' in '{}'", line, path.display()))?;
+            self.debug(&cmd)?;
+        }
+        Ok(())
+    }
+
+    fn save_breakpoints(&self) -> Result<()> {
+        let path = self.sidecar();
+        let mut file = File::create(&path)
+            .chain_err(|| format!("Failed to create file '{}'", path.display()))?;
+        let mut text = String::new();
+        for bp in &self.breakpoints {
+            text.push_str(&bp.command());
+            text.push('\n');
+        }
+        // ids start over when the file is read back
+        for (i, bp) in self.breakpoints.iter().enumerate() {
+            if !bp.enabled {
+                text.push_str(&format!("disable {}\n", i + 1));
+            }
+        }
+        file.write_all(text.as_bytes())
+            .chain_err(|| format!("Unable to Write file '{}'", path.display()))?;
+        println!("Saved {} breakpoints to '{}'", self.breakpoints.len(), path.display());
+        Ok(())
+    }
+
+    /// The code address of a location
+    fn resolve(&self, location: &Location) -> Result<usize> {
+        match *location {
+            Location::Pc(pc) if pc < self.machine.code.len() => Ok(pc),
+            Location::Pc(pc) => bail!("No instruction at pc {}", pc),
+            Location::Label(ref label) => match self.machine.labels.get(label) {
+                Some(&pc) => Ok(pc),
+                None => bail!("Undefined label '{}'", label),
+            },
+        }
+    }
+
+    /// The code address or stack slot of a trigger
+    fn address(&self, trigger: &Trigger) -> Result<usize> {
+        match *trigger {
+            Trigger::Code(ref location) => self.resolve(location),
+            Trigger::Write(ref slot) | Trigger::Read(ref slot) => {
+                slot.address(self.machine, self.fp())
+            }
+        }
+    }
+
+    /// Add a breakpoint, returning its id
+    fn add(&mut self, trigger: Trigger, condition: Option<Expr>) -> Result<usize> {
+        let bp = Breakpoint {
+            id: self.next_id,
+            address: self.address(&trigger)?,
+            trigger,
+            condition,
+            enabled: true,
+            hits: 0,
+        };
+        self.next_id += 1;
+        self.breakpoints.push(bp);
+        Ok(self.next_id - 1)
+    }
+
+    /// The first label, in alphabetical order, of a code address
+    fn label_at(&self, pc: usize) -> Option<&str> {
+        self.machine
+            .labels
+            .iter()
+            .filter(|&(_, &v)| v == pc)
+            .map(|(k, _)| k.as_str())
+            .min()
+    }
+
+    /// Describe a code address with its label and source location
+    fn describe(&self, pc: usize) -> String {
+        let mut res = format!("pc = {}", pc);
+        if let Some(label) = self.label_at(pc) {
+            res.push_str(&format!(" ({})", label));
+        }
+        if let Some(span) = self.machine.spans.get(pc) {
+            res.push_str(&format!(" at {}", span));
+        }
+        res
+    }
+
+    /// The breakpoints a command with an optional id applies to
+    fn selected(&mut self, id: Option<usize>) -> Result<Vec<&mut Breakpoint>> {
+        let selected: Vec<&mut Breakpoint> = self.breakpoints
+            .iter_mut()
+            .filter(|bp| id.is_none_or(|id| bp.id == id))
+            .collect();
+        match id {
+            Some(id) if selected.is_empty() => bail!("No breakpoint number {}", id),
+            _ => Ok(selected),
+        }
+    }
+
+    /// Run until the program ends or reaches an enabled breakpoint
+    fn cont(&mut self) -> Result<Status> {
+        self.run_until(|_| false).map(Stop::status)
+    }
+
+    /// Run until the program ends, reaches an enabled breakpoint or `done`
+    /// holds after an instruction
+    fn run_until<F: Fn(&Machine) -> bool>(&mut self, done: F) -> Result<Stop> {
+        // as the loop only checks after each instruction, check where it
+        // starts too: the entry, or a pc set by hand
+        let pc = self.machine.pc;
+        if self.stopped_at != Some((self.machine.steps, pc)) {
+            if let Some(bp) = self.hit(|bp| bp.is_code(pc)) {
+                self.stopped_at = Some((self.machine.steps, pc));
+                self.show_breakpoint(&bp)?;
+                return Ok(Stop::Breakpoint);
+            }
+        }
+
+        let started = Instant::now();
+        loop {
+            self.machine.check_steps()?;
+            self.machine.check_time(started)?;
+            let pc = self.machine.pc;
+            let access = self.machine.access();
+            let old = match access {
+                Some(Access::Write(slot)) => self.machine.stack.get(slot).cloned(),
+                _ => None,
+            };
+            if let Status::Exit = self.history.forward(self.machine)? {
+                println!();
+                return Ok(Stop::Exit);
+            }
+
+            let new = access.and_then(|a| match a {
+                Access::Read(slot) | Access::Write(slot) => self.machine.stack.get(slot).cloned(),
+            });
+            if let Some(bp) = access.and_then(|a| self.watched(a)) {
+                println!();
+                self.report(&bp, pc, old, new);
+                println!("\t< {} >", self.machine.get_instruction()?);
+                return Ok(Stop::Breakpoint);
+            }
+
+            let pc = self.machine.pc;
+            if let Some(bp) = self.hit(|bp| bp.is_code(pc)) {
+                self.show_breakpoint(&bp)?;
+                return Ok(Stop::Breakpoint);
+            }
+            if done(self.machine) {
+                return Ok(Stop::Done);
+            }
+        }
+    }
+
+    /// Show the code breakpoint the program stopped at
+    fn show_breakpoint(&self, bp: &Breakpoint) -> Result<()> {
+        println!();
+        println!("Breakpoint {}, {}", bp.id, self.describe(self.machine.pc));
+        println!("\t< {} >", self.machine.get_instruction()?);
+        Ok(())
+    }
+
+    /// Run the instruction at `pc`, and the function it calls until it returns
+    fn over(&mut self) -> Result<Status> {
+        let instr = self.machine.get_instruction()?;
+        println!("\t< {} >", instr);
+        let replaying = self.history.replaying(self.machine);
+        let depth = self.machine.call_stack.len();
+        let stop = self.run_until(|m| m.call_stack.len() <= depth)?;
+        if let (Stop::Done, false) = (stop, replaying) {
+            instr.write_ln();
+        }
+        Ok(stop.status())
+    }
+
+    /// Run until the function called at `depth` returns
+    fn finish(&mut self, depth: usize) -> Result<Status> {
+        let stop = self.run_until(|m| m.call_stack.len() < depth)?;
+        if let Stop::Done = stop {
+            println!();
+            println!("Returned from the function");
+            self.show_step();
+        }
+        Ok(stop.status())
+    }
+
+    /// Run until the code address `pc` or the return of the current function
+    fn until(&mut self, pc: usize) -> Result<Status> {
+        let depth = self.machine.call_stack.len();
+        let stop = self.run_until(|m| m.pc == pc || m.call_stack.len() < depth)?;
+        if let Stop::Done = stop {
+            println!();
+            self.show_step();
+        }
+        Ok(stop.status())
+    }
+
+    /// The first watchpoint on a slot an instruction uses
+    fn watched(&mut self, access: Access) -> Option<Breakpoint> {
+        self.hit(|bp| match (&bp.trigger, access) {
+            (&Trigger::Write(..), Access::Write(slot)) | (&Trigger::Read(..), Access::Read(slot)) => {
+                bp.address == slot
+            }
+            _ => false,
+        })
+    }
+
+    /// Show the values a watchpoint stopped on, for the instruction at `pc`
+    fn report(&self, bp: &Breakpoint, pc: usize, old: Option<Operand>, new: Option<Operand>) {
+        let (kind, slot, verb) = match bp.trigger {
+            Trigger::Read(ref slot) => ("Read watchpoint", slot, "read"),
+            Trigger::Write(ref slot) => ("Watchpoint", slot, "written"),
+            Trigger::Code(..) => unreachable!("not a watchpoint"),
+        };
+        println!("{} {}: {}, {} by {}", kind, bp.id, slot, verb, self.describe(pc));
+        if let Some(old) = old {
+            println!("Old value = {}", Value::from(old));
+        }
+        if let Some(new) = new {
+            let name = if old.is_some() { "New value" } else { "Value" };
+            println!("{} = {}", name, Value::from(new));
+        }
+    }
+
+    /// Show the current step and the instruction at `pc`
+    fn show_step(&self) {
+        println!("Step {}, {}", self.machine.steps, self.describe(self.machine.pc));
+        if let Ok(instr) = self.machine.get_instruction() {
+            println!("\t< {} >", instr);
+        }
+    }
+
+    /// Run backwards until the start of the history or a breakpoint, which
+    /// stops before the instruction at it runs as it does going forward
+    fn reverse_cont(&mut self) -> Result<()> {
+        while self.machine.steps > self.history.start() {
+            // the watched slots as the instruction left them, to show what it wrote
+            let written: Vec<(usize, Option<Operand>)> = self.breakpoints
+                .iter()
+                .filter(|bp| matches!(bp.trigger, Trigger::Write(..)))
+                .map(|bp| (bp.address, self.machine.stack.get(bp.address).cloned()))
+                .collect();
+            let step = self.machine.steps - 1;
+            self.history.rewind(self.machine, step)?;
+
+            let pc = self.machine.pc;
+            let access = self.machine.access();
+            let old = match access {
+                Some(Access::Write(slot)) => self.machine.stack.get(slot).cloned(),
+                _ => None,
+            };
+            let new = match access {
+                Some(Access::Read(slot)) => self.machine.stack.get(slot).cloned(),
+                Some(Access::Write(slot)) => written.iter().find(|w| w.0 == slot).and_then(|w| w.1),
+                None => None,
+            };
+            if let Some(bp) = access.and_then(|a| self.watched(a)) {
+                self.report(&bp, pc, old, new);
+                self.show_step();
+                return Ok(());
+            }
+            if let Some(bp) = self.hit(|bp| bp.is_code(pc)) {
+                println!("Breakpoint {}, {}", bp.id, self.describe(pc));
+                self.show_step();
+                return Ok(());
+            }
+        }
+        println!("Reached the start of the history");
+        self.show_step();
+        Ok(())
+    }
+
+    /// Go to the state before instruction number `step` runs, backwards or
+    /// forwards, without stopping at breakpoints
+    fn goto_step(&mut self, step: u64) -> Result<Status> {
+        let mut status = self.status;
+        if step < self.machine.steps {
+            self.history.rewind(self.machine, step)?;
+            status = Status::Success;
+        } else if step > self.machine.steps {
+            if let Status::Exit = status {
+                bail!("The program finished at step {}", self.machine.steps);
+            }
+            let started = Instant::now();
+            while self.machine.steps < step {
+                self.machine.check_steps()?;
+                self.machine.check_time(started)?;
+                if let Status::Exit = self.history.forward(self.machine)? {
+                    println!();
+                    println!("The program finished at step {}", self.machine.steps);
+                    return Ok(Status::Exit);
+                }
+            }
+        }
+        self.show_step();
+        Ok(status)
+    }
+
+    /// The first enabled breakpoint matching `f` whose condition holds.
+    /// A condition that fails to evaluate stops as well.
+    fn hit<F: Fn(&Breakpoint) -> bool>(&mut self, f: F) -> Option<Breakpoint> {
+        let m = &*self.machine;
+        for bp in self.breakpoints.iter_mut().filter(|bp| bp.enabled && f(bp)) {
+            if let Some(ref cond) = bp.condition {
+                let id = bp.id;
+                match cond.eval(m, m.fp)
+                    .and_then(|v| v.truth())
+                    .chain_err(|| format!("Error in the condition of breakpoint {}", id))
+                {
+                    Ok(false) => continue,
+                    Ok(true) => {}
+                    Err(ref e) => errors::print_errors(e),
+                }
+            }
+            bp.hits += 1;
+            return Some(bp.clone());
+        }
+        None
+    }
+
+    /// Run `count` instructions, showing each one
+    fn step(&mut self, count: usize) -> Result<Status> {
+        let started = Instant::now();
+        for _ in 0..count {
+            self.machine.check_steps()?;
+            self.machine.check_time(started)?;
+            let instr = &self.machine.get_instruction()?;
+            println!("\t< {} >", instr);
+            let replaying = self.history.replaying(self.machine);
+            let s = self.history.forward(self.machine)?;
+            if !replaying {
+                instr.write_ln();
+            }
+            if let Status::Exit = s {
+                return Ok(s);
+            }
+        }
+        Ok(Status::Success)
+    }
+
+    /// Load the program again, keeping the breakpoints
+    fn restart(&mut self) -> Result<()> {
+        let mut machine = Machine::new(self.machine.options.clone());
+        machine.load(&self.path)?;
+        *self.machine = machine;
+        self.history = History::new(self.machine);
+        self.frame = 0;
+        self.failure = None;
+        self.stopped_at = None;
+        self.status = Status::Success;
+        println!("Restarted '{}'", self.path.display());
+
+        // labels can have moved if the file changed
+        for i in 0..self.breakpoints.len() {
+            match self.address(&self.breakpoints[i].trigger) {
+                Ok(address) => self.breakpoints[i].address = address,
+                Err(e) => {
+                    let bp = &mut self.breakpoints[i];
+                    bp.enabled = false;
+                    println!("Breakpoint {} disabled: {}", bp.id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The frames of the calls in progress, the innermost first
+    fn frames(&self) -> Vec<Frame> {
+        let m = &self.machine;
+        let mut frames = Vec::with_capacity(m.call_stack.len() + 1);
+        let (mut pc, mut fp) = (m.pc, m.fp);
+        for &(call, caller_fp) in m.call_stack.iter().rev() {
+            let function = Some(self.callee(call, pc));
+            frames.push(Frame { pc, fp, function });
+            pc = call + 1;
+            fp = caller_fp;
+        }
+        frames.push(Frame {
+            pc,
+            fp,
+            function: None,
+        });
+        frames
+    }
+
+    /// The entry label of the function called at `call`, now running at `pc`
+    fn callee(&self, call: usize, pc: usize) -> String {
+        let code = &self.machine.code;
+        if let Some(Instruction::Pusha(label)) = call.checked_sub(1).and_then(|i| code.get(i)) {
+            return label.clone();
+        }
+        // the address was computed, so take the closest label before `pc`
+        self.machine
+            .labels
+            .iter()
+            .filter(|&(_, &v)| v <= pc)
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map_or_else(|| "?".to_string(), |(k, _)| k.clone())
+    }
+
+    /// The frame pointer of the selected frame
+    fn fp(&self) -> usize {
+        let frames = self.frames();
+        frames[self.frame.min(frames.len() - 1)].fp
+    }
+
+    /// A stack slot with what its value points to, when it is an address:
+    /// the text of a string or the label of code
+    fn show_slot(&self, name: &str, v: Operand) {
+        let mut what = Vec::new();
+        if let Operand::Address(a) = v {
+            if let Some(s) = self.machine.strings.get(a) {
+                what.push(escape(s));
+            }
+            if let Some(label) = self.label_at(a) {
+                what.push(format!("<{}>", label));
+            }
+        }
+        if what.is_empty() {
+            println!("{:>10} = {}", name, v);
+        } else {
+            println!("{:>10} = {}  {}", name, v, what.join(" or "));
+        }
+    }
+
+    fn show_frame(&self, n: usize, frame: &Frame) {
+        let name = match frame.function {
+            Some(ref function) => function.as_str(),
+            None => "top level",
+        };
+        let at = if n == 0 { "at" } else { "returning to" };
+        println!("#{:<3} {} (fp = {}) {} {}", n, name, frame.fp, at, self.describe(frame.pc));
+    }
+
+    /// Select frame `n`, counted from the innermost
+    fn select(&mut self, n: usize) -> Result<()> {
+        let frames = self.frames();
+        match frames.get(n) {
+            Some(frame) => self.show_frame(n, frame),
+            None => bail!("No frame {}, the outermost is #{}", n, frames.len() - 1),
+        }
+        self.frame = n;
+        Ok(())
+    }
+
+    /// Start the history again after the state was changed by hand, since
+    /// going back or forward from here would not be the same run
+    fn edited(&mut self) {
+        self.history = History::new(self.machine);
+    }
+
+    /// Show the error that ended the program
+    fn stopped(&mut self, e: &Error) -> Status {
+        errors::print_errors(e);
+        self.failure = Some((self.machine.steps, errors::exit_code(e)));
+        Status::Exit
+    }
+
+    /// The exit code of the session, which is always 0 unless in batch mode.
+    /// There it is 1 when a command file failed, and otherwise the code of
+    /// the error that ended the program as it is now.
+    fn exit_code(&self, failed: bool) -> i32 {
+        if !self.machine.options.batch {
+            return 0;
+        }
+        match (self.status, self.failure) {
+            _ if failed => errors::EXIT_FAILURE,
+            (Status::Exit, Some((steps, code))) if steps == self.machine.steps => code,
+            _ => 0,
+        }
+    }
+
+    fn prompt(&self) -> &'static str {
+        match self.status {
+            Status::Success => "(debug) ",
+            Status::Exit => "(debug - finished) ",
+        }
+    }
+
+    /// Run the commands in a file, stopping at the first one that fails
+    fn source(&mut self, path: &Path) -> Result<()> {
+        if self.sourcing >= MAX_SOURCING {
+            bail!("Command files are nested more than {} deep", MAX_SOURCING);
+        }
+        let text = fs::read_to_string(path)
+            .chain_err(|| format!("Unable to Read file '{}'", path.display()))?;
+
+        self.sourcing += 1;
+        let mut res = Ok(());
+        for (i, line) in text.lines().enumerate() {
+            res = self.script(line)
+                .chain_err(|| format!("In '{}' at line {}", path.display(), i + 1));
+            if res.is_err() || self.quit {
+                break;
+            }
+        }
+        self.sourcing -= 1;
+        res
+    }
+
+    /// Run a line of a command file, shown as if it was typed
+    fn script(&mut self, line: &str) -> Result<()> {
+        let cmd = line.parse()?;
+        if let Command::Empty = cmd {
+            return Ok(());
+        }
+        println!("{}{}", self.prompt(), line.trim());
+        self.status = self.debug(&cmd)?;
+        Ok(())
+    }
+
+    /// Run a command, going back to the innermost frame if the program moved
+    /// and remembering where it stopped
+    fn debug(&mut self, cmd: &Command) -> Result<Status> {
+        let steps = self.machine.steps;
+        let res = self.execute(cmd);
+        let moved = self.machine.steps != steps;
+        if moved {
+            self.frame = 0;
+        }
+        // a restart starts afresh, and the commands of a file remember their own stops
+        if moved && !matches!(*cmd, Command::Restart | Command::Source(..)) {
+            self.stopped_at = Some((self.machine.steps, self.machine.pc));
+        }
+        res
+    }
+
+    fn execute(&mut self, cmd: &Command) -> Result<Status> {
+        let status = self.status;
+        match *cmd {
+            Command::PrintCode => {
+                println!("\t/// CODE ///");
+                for (i, line) in self.machine.code.iter().enumerate() {
+                    for (k, _) in self.machine.labels.iter().filter(|&(_, &v)| v == i) {
+                        println!("{}:", k);
+                    }
+                    let mark = if self.breakpoints.iter().any(|bp| bp.is_code(i)) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    println!(" {}{:>2}|\t{}", mark, i, line);
+                }
+                Ok(status)
+            }
+            Command::PrintLabels => {
+                println!("Labels:");
+                for (k, val) in &self.machine.labels {
+                    println!(" | {} => {}", k, val);
+                }
+                Ok(status)
+            }
+            Command::PrintRegisters => {
+                let m = &self.machine;
+                print!("Registeres: ");
+                print!(" sp = {:2} |", m.sp());
+                print!(" fp = {:2} |", m.fp);
+                print!(" pc = {:2} |", m.pc);
+                print!(" gp = {:2} |", m.gp);
+                print!(" cost = {} |", m.cost);
+                println!();
+                Ok(status)
+            }
+            Command::PrintStack => {
+                let m = &self.machine;
+                let fp = self.fp();
+                match self.frame {
+                    0 => println!("Stack:"),
+                    n => println!("Stack, with the fp of frame #{}:", n),
+                }
+                print!("--- <- sp");
+                if fp == m.sp() {
+                    print!(" <- fp");
+                }
+                println!();
+
+                for (i, val) in m.stack.iter().enumerate().rev() {
+                    print!("{}", val);
+                    if fp == i {
+                        print!(" <- fp");
+                    }
+                    println!();
+                }
+                Ok(status)
+            }
+            Command::Run => match status {
+                Status::Success => Ok(self.cont().unwrap_or_else(|ref e| self.stopped(e))),
+                Status::Exit => Ok(status),
+            },
+            Command::Over | Command::Finish | Command::Until(..) => {
+                if let Status::Exit = status {
+                    return Ok(status);
+                }
+                let res = match *cmd {
+                    Command::Over => self.over(),
+                    Command::Finish => match self.machine.call_stack.len() {
+                        0 => bail!("Not in a function, there is nothing to return from"),
+                        depth => self.finish(depth),
+                    },
+                    Command::Until(ref location) => {
+                        let pc = self.resolve(location)?;
+                        self.until(pc)
+                    }
+                    _ => unreachable!("not a command that runs"),
+                };
+                Ok(res.unwrap_or_else(|ref e| self.stopped(e)))
+            }
+            Command::Next(end) => {
+                if let Status::Success = status {
+                    let mut bk = self.machine.clone();
+                    for _ in 0..end {
+                        let instr = bk.get_instruction()?;
+                        println!("\t: {} :", instr);
+                        instr.write_ln();
+                        if let Status::Exit = bk.run_instruction()? {
+                            break;
+                        }
+                    }
+                }
+                Ok(status)
+            }
+            Command::Step(end) => {
+                if let Status::Success = status {
+                    return Ok(self.step(end).unwrap_or_else(|ref e| self.stopped(e)));
+                }
+                Ok(status)
+            }
+            Command::Back(count) => {
+                let start = self.history.start();
+                if self.machine.steps == start {
+                    bail!("Already at the start of the history");
+                }
+                let step = self.machine.steps.saturating_sub(count).max(start);
+                self.history.rewind(self.machine, step)?;
+                self.show_step();
+                Ok(Status::Success)
+            }
+            Command::ReverseContinue => {
+                self.reverse_cont()?;
+                Ok(Status::Success)
+            }
+            Command::GotoStep(step) => Ok(self.goto_step(step).unwrap_or_else(|ref e| self.stopped(e))),
+            Command::Break(ref location, ref condition) => {
+                let trigger = Trigger::Code(location.clone());
+                let id = self.add(trigger, condition.clone())?;
+                let pc = self.breakpoints[self.breakpoints.len() - 1].address;
+                println!("Breakpoint {} at {}", id, self.describe(pc));
+                Ok(status)
+            }
+            Command::Watch(ref slot, ref condition) | Command::Rwatch(ref slot, ref condition) => {
+                let trigger = match *cmd {
+                    Command::Watch(..) => Trigger::Write(slot.clone()),
+                    _ => Trigger::Read(slot.clone()),
+                };
+                let id = self.add(trigger, condition.clone())?;
+                let address = self.breakpoints[self.breakpoints.len() - 1].address;
+                match *cmd {
+                    Command::Watch(..) => println!("Watchpoint {}: {} (slot {})", id, slot, address),
+                    _ => println!("Read watchpoint {}: {} (slot {})", id, slot, address),
+                }
+                Ok(status)
+            }
+            Command::Condition(id, ref condition) => {
+                for bp in self.selected(Some(id))? {
+                    bp.condition = condition.clone();
+                }
+                match *condition {
+                    Some(_) => println!("Breakpoint {} is now conditional", id),
+                    None => println!("Breakpoint {} is now unconditional", id),
+                }
+                Ok(status)
+            }
+            Command::Print(ref e) => {
+                println!("{}", e.eval(self.machine, self.fp())?);
+                Ok(status)
+            }
+            Command::Examine(ref addr, count) => {
+                let m = &self.machine;
+                let start = match addr.eval(m, self.fp())?.index()? {
+                    i if i < 0 || i as usize >= m.sp() => {
+                        bail!("Slot {} is out of the stack (sp = {})", i, m.sp())
+                    }
+                    i => i as usize,
+                };
+                let end = m.sp().min(start.saturating_add(count));
+                for i in start..end {
+                    self.show_slot(&format!("stack[{}]", i), m.stack[i]);
+                }
+                if end - start < count {
+                    println!("(the stack ends at sp = {})", m.sp());
+                }
+                Ok(status)
+            }
+            Command::PrintStrings => {
+                println!("Strings:");
+                for (i, s) in self.machine.strings.iter().enumerate() {
+                    println!("{:>5}a  {}", i, escape(s));
+                }
+                Ok(status)
+            }
+            Command::PrintGlobals => {
+                let m = &self.machine;
+                // the globals are under the frame of the top level
+                let end = self.frames().last().map_or(m.gp, |f| f.fp).min(m.sp());
+                if end <= m.gp {
+                    println!("No globals, they are the values pushed before 'start'");
+                }
+                for i in m.gp..end {
+                    self.show_slot(&format!("gp[{}]", i - m.gp), m.stack[i]);
+                }
+                Ok(status)
+            }
+            Command::SetPc(ref location) => {
+                let pc = self.resolve(location)?;
+                self.machine.pc = pc;
+                self.edited();
+                println!("{}", self.describe(pc));
+                Ok(Status::Success)
+            }
+            Command::SetFp(ref e) => {
+                let fp = match e.eval(self.machine, self.fp())?.index()? {
+                    i if i < 0 || i as usize > self.machine.sp() => {
+                        bail!("fp must be in the stack, from 0 to sp = {}", self.machine.sp())
+                    }
+                    i => i as usize,
+                };
+                self.machine.fp = fp;
+                self.edited();
+                Ok(status)
+            }
+            Command::SetSlot(ref slot, ref e) => {
+                let fp = self.fp();
+                let i = slot.slot(self.machine, fp)?;
+                let v = e.eval(self.machine, fp)?.operand()?;
+                let old = ::std::mem::replace(&mut self.machine.stack[i], v);
+                self.edited();
+                println!("stack[{}] = {} (was {})", i, Value::from(v), Value::from(old));
+                Ok(status)
+            }
+            Command::Push(ref e) => {
+                let v = e.eval(self.machine, self.fp())?.operand()?;
+                self.machine.stack.push(v);
+                self.edited();
+                Ok(status)
+            }
+            Command::Pop => {
+                let v = self.machine.stack.pop().ok_or("The stack is empty")?;
+                self.edited();
+                println!("{}", Value::from(v));
+                Ok(status)
+            }
+            Command::Backtrace => {
+                for (n, frame) in self.frames().iter().enumerate() {
+                    self.show_frame(n, frame);
+                }
+                Ok(status)
+            }
+            Command::Frame(n) => {
+                let n = n.unwrap_or(self.frame);
+                self.select(n)?;
+                Ok(status)
+            }
+            Command::Up(count) => {
+                let n = self.frame + count;
+                self.select(n)?;
+                Ok(status)
+            }
+            Command::Down(count) => {
+                if count > self.frame {
+                    bail!("Already at the innermost frame");
+                }
+                let n = self.frame - count;
+                self.select(n)?;
+                Ok(status)
+            }
+            Command::Delete(id) => {
+                self.selected(id)?;
+                self.breakpoints.retain(|bp| id.is_some_and(|id| bp.id != id));
+                Ok(status)
+            }
+            Command::Enable(id) => {
+                for bp in self.selected(id)? {
+                    bp.enabled = true;
+                }
+                Ok(status)
+            }
+            Command::Disable(id) => {
+                for bp in self.selected(id)? {
+                    bp.enabled = false;
+                }
+                Ok(status)
+            }
+            Command::InfoBreakpoints => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                    return Ok(status);
+                }
+                println!("{:<4} {:<4} {:<8} {:<6} What", "Id", "Enb", "At", "Hits");
+                for bp in &self.breakpoints {
+                    let enabled = if bp.enabled { "y" } else { "n" };
+                    let at = match bp.trigger {
+                        Trigger::Code(..) => format!("pc {}", bp.address),
+                        _ => format!("slot {}", bp.address),
+                    };
+                    print!("{:<4} {:<4} {:<8} {:<6} {}", bp.id, enabled, at, bp.hits, bp.trigger);
+                    match bp.condition {
+                        Some(ref cond) => println!(" if {}", cond),
+                        None => println!(),
+                    }
+                }
+                Ok(status)
+            }
+            Command::Save => {
+                self.save_breakpoints()?;
+                Ok(status)
+            }
+            Command::Restart => {
+                self.restart()?;
+                Ok(self.status)
+            }
+            Command::Source(ref path) => {
+                self.source(Path::new(path))?;
+                Ok(self.status)
+            }
+            Command::Help => {
+                Command::help();
+                Ok(status)
+            }
+            Command::Quit => {
+                self.quit = true;
+                Ok(status)
+            }
+            Command::Empty => Ok(status),
+        }
+    }
+
+    /// Run the command files, then the commands typed until `quit`,
+    /// returning the exit code of the session
+    pub fn run(&mut self) -> Result<i32> {
+        // a batch run only depends on its command files
+        if !self.machine.options.batch {
+            if let Err(ref e) = self.load_breakpoints() {
+                errors::print_errors(e);
+            }
+        }
+
+        let mut failed = false;
+        for path in self.machine.options.commands.clone() {
+            if self.quit {
+                break;
+            }
+            if let Err(ref e) = self.source(Path::new(&path)) {
+                errors::print_errors(e);
+                failed = true;
+            }
+        }
+        if self.machine.options.batch || self.quit {
+            return Ok(self.exit_code(failed));
+        }
+
+        let mut prompt = Prompt::new()?;
+        // the command an empty line runs again
+        let mut last: Option<Command> = None;
+        loop {
+            prompt.set_labels(self.machine.labels.keys().cloned().collect());
+            let (line, cmd) = match prompt.readline(self.prompt()) {
+                Ok(Some(line)) => {
+                    let cmd = line.parse();
+                    (line, cmd)
+                }
+                Ok(None) => {
+                    println!();
+                    (String::new(), Ok(Command::Quit))
+                }
+                Err(e) => (String::new(), Err(e)),
+            };
+            let cmd = match cmd {
+                // a comment is not an empty line
+                Ok(Command::Empty) if line.trim().is_empty() => match last {
+                    Some(ref cmd) => cmd.clone(),
+                    None => continue,
+                },
+                Ok(Command::Empty) => continue,
+                Ok(cmd) => cmd,
+                Err(ref e) => {
+                    errors::print_errs(e);
+                    continue;
+                }
+            };
+            last = if cmd.repeats() { Some(cmd.clone()) } else { None };
+
+            // errors of the program end it, errors of the command are only shown
+            match self.debug(&cmd) {
+                Ok(status) => self.status = status,
+                Err(ref e) => errors::print_errors(e),
+            }
+            if self.quit {
+                return Ok(self.exit_code(failed));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::Debugger;
+    use commands::Status;
+    use vm::{Machine, Operand, Options};
+
+    /// Calls `inc` twice from `twice`, which adds 1 to `gp[0]`
+    const CODE: &str = "pushi 0\nstart\npusha twice\ncall\npushs \"done\"\nwrites\nstop\n\
+                        twice: pusha inc\ncall\npusha inc\ncall\npushs \"in twice\"\nwrites\nreturn\n\
+                        inc: pushg 0\npushi 1\nadd\nstoreg 0\nreturn\n";
+
+    /// Write a program to a file of its own, as `restart` loads it again
+    fn write(name: &str, code: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("pl-vm-{}-{}.vm", process::id(), name));
+        fs::write(&path, code).unwrap();
+        path
+    }
+
+    fn load(path: &PathBuf, options: Options) -> Machine {
+        let mut m = Machine::new(options);
+        m.load(path).unwrap();
+        m
+    }
+
+    /// Run commands as a command file would
+    fn run(d: &mut Debugger, lines: &[&str]) {
+        for line in lines {
+            d.script(line).unwrap();
+        }
+    }
+
+    #[test]
+    fn breakpoints() {
+        let path = write("breakpoints", CODE);
+        let mut m = load(&path, Options::default());
+        let mut d = Debugger::new(&mut m, &path);
+
+        // a breakpoint at the entry stops before anything runs, and only once
+        run(&mut d, &["b 0", "b inc", "r"]);
+        assert_eq!((d.machine.steps, d.machine.pc), (0, 0));
+        run(&mut d, &["r"]);
+        assert_eq!((d.machine.steps, d.machine.pc), (6, 14));
+        run(&mut d, &["disable 2", "r"]);
+        assert!(matches!(d.status, Status::Exit));
+        assert_eq!(d.machine.stack[0], Operand::Integer(2));
+        assert_eq!(d.breakpoints[1].hits, 1);
+
+        // so does one at a pc set by hand
+        run(&mut d, &["restart", "delete 1", "enable 2", "set pc twice", "b twice", "r"]);
+        assert_eq!((d.machine.steps, d.machine.pc), (0, 7));
+        run(&mut d, &["r"]);
+        assert_eq!(d.machine.pc, 14);
+
+        // the labels are resolved again in the new file
+        fs::write(&path, CODE.replace("inc: ", "nop\ninc: ").replace("twice", "again")).unwrap();
+        run(&mut d, &["restart"]);
+        assert_eq!((d.breakpoints[0].address, d.breakpoints[0].enabled), (15, true));
+        assert!(!d.breakpoints[1].enabled);
+        run(&mut d, &["r"]);
+        assert_eq!(d.machine.pc, 15);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn watchpoints() {
+        let path = write("watchpoints", CODE);
+        let mut m = load(&path, Options::default());
+        let mut d = Debugger::new(&mut m, &path);
+
+        // stops after the instruction that wrote the slot
+        run(&mut d, &["s 2", "watch gp[0]", "r"]);
+        assert_eq!(d.machine.pc, 18);
+        assert_eq!(d.machine.stack[0], Operand::Integer(1));
+
+        // a read of the slot
+        run(&mut d, &["delete", "rwatch gp[0]", "r"]);
+        assert_eq!(d.machine.pc, 15);
+        assert_eq!(d.machine.stack[0], Operand::Integer(1));
+
+        // going back stops before the instruction, as it would going forward
+        run(&mut d, &["delete", "watch gp[0]", "r", "r", "reverse-continue"]);
+        assert_eq!((d.machine.pc, d.machine.steps), (17, 16));
+        assert_eq!(d.machine.stack[0], Operand::Integer(1));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stepping() {
+        let path = write("stepping", CODE);
+        let mut m = load(&path, Options::default());
+        let mut d = Debugger::new(&mut m, &path);
+
+        run(&mut d, &["until twice"]);
+        assert_eq!(d.machine.pc, 7);
+        // over a call, which runs the whole function
+        run(&mut d, &["over", "over"]);
+        assert_eq!(d.machine.pc, 9);
+        assert_eq!(d.machine.stack[0], Operand::Integer(1));
+        run(&mut d, &["s 2"]);
+        assert_eq!(d.machine.pc, 14);
+        run(&mut d, &["finish"]);
+        assert_eq!(d.machine.pc, 11);
+        assert_eq!(d.machine.stack[0], Operand::Integer(2));
+        // until stops at the return of the function too
+        run(&mut d, &["until 0"]);
+        assert_eq!((d.machine.pc, d.machine.call_stack.len()), (4, 0));
+        assert!(d.script("finish").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn step_budget() {
+        let path = write("step_budget", CODE);
+        let mut m = load(&path, Options { max_steps: Some(3), ..Options::default() });
+        let mut d = Debugger::new(&mut m, &path);
+
+        run(&mut d, &["s 10"]);
+        assert!(matches!(d.status, Status::Exit));
+        assert_eq!((d.machine.steps, d.failure), (3, Some((3, 124))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn frames() {
+        let path = write("frames", CODE);
+        let mut m = load(&path, Options::default());
+        let mut d = Debugger::new(&mut m, &path);
+
+        run(&mut d, &["b inc", "r"]);
+        let frames = d.frames();
+        let functions: Vec<Option<&str>> = frames.iter().map(|f| f.function.as_deref()).collect();
+        assert_eq!(functions, [Some("inc"), Some("twice"), None]);
+        assert_eq!(frames.iter().map(|f| f.pc).collect::<Vec<_>>(), [14, 9, 4]);
+
+        run(&mut d, &["bt", "frame 1"]);
+        assert_eq!((d.frame, d.fp()), (1, frames[1].fp));
+        assert!(d.script("up 2").is_err());
+        run(&mut d, &["up", "down 2"]);
+        assert_eq!(d.frame, 0);
+        // moving the program goes back to the innermost frame
+        run(&mut d, &["up", "s"]);
+        assert_eq!(d.frame, 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn examine() {
+        let path = write("examine", CODE);
+        let mut m = load(&path, Options::default());
+        let mut d = Debugger::new(&mut m, &path);
+
+        run(&mut d, &["globals", "until twice", "x gp 3", "x 0", "globals", "strings"]);
+        assert!(d.script("x sp").is_err());
+        assert!(d.script("x -1").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,631 @@
+//! Expressions over the state of the `vm`, for debug commands
+//!
+//! ```text
+//! expr    ::= and ("||" and)*
+//! and     ::= compare ("&&" compare)*
+//! compare ::= sum (("==" | "!=" | "<" | "<=" | ">" | ">=") sum)?
+//! sum     ::= product (("+" | "-") product)*
+//! product ::= unary (("*" | "/" | "%") unary)*
+//! unary   ::= ("-" | "!") unary | primary
+//! primary ::= <integer> | <address> | <string> | "(" expr ")" | <register> | <label>
+//!           | ("stack" | "gp" | "fp") "[" expr "]" | "str" "(" expr ")"
+//! ```
+//!
+//! An address is written as it is shown, as an integer followed by `a`.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use errors::*;
+
+use instructions::escape;
+use parser;
+use vm::{Machine, Operand};
+
+/// The value of an expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Address(usize),
+    Str(String),
+}
+
+impl From<Operand> for Value {
+    fn from(o: Operand) -> Self {
+        match o {
+            Operand::Integer(i) => Value::Integer(i64::from(i)),
+            Operand::Address(a) => Value::Address(a),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Address(a) => write!(f, "{}a", a),
+            Value::Str(ref s) => write!(f, "{}", escape(s)),
+        }
+    }
+}
+
+impl Value {
+    /// The value as a stack slot index
+    pub fn index(&self) -> Result<i64> {
+        match *self {
+            Value::Integer(i) => Ok(i),
+            Value::Address(a) => Ok(a as i64),
+            Value::Str(..) => bail!("A string is not an index"),
+        }
+    }
+
+    /// The value as it is stored in the stack
+    pub fn operand(&self) -> Result<Operand> {
+        match *self {
+            Value::Integer(i) if i < i64::from(i32::MIN) || i > i64::from(i32::MAX) => {
+                bail!("{} doesn't fit in an integer operand", i)
+            }
+            Value::Integer(i) => Ok(Operand::Integer(i as i32)),
+            Value::Address(a) => Ok(Operand::Address(a)),
+            Value::Str(..) => bail!("A string can't be stored in the stack, only its address"),
+        }
+    }
+
+    /// Whether the value counts as true in a condition
+    pub fn truth(&self) -> Result<bool> {
+        match *self {
+            Value::Integer(i) => Ok(i != 0),
+            _ => bail!("{} is not an integer", self),
+        }
+    }
+}
+
+/// The names with a meaning of their own in expressions
+pub const NAMES: &[&str] = &["cost", "fp", "gp", "pc", "sp", "stack", "steps", "str"];
+
+/// Values that can be read by name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    Sp,
+    Fp,
+    Pc,
+    Gp,
+    Steps,
+    Cost,
+}
+
+/// Regions of the operand stack that can be indexed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Area {
+    /// Absolute slots, counted from the top when negative
+    Stack,
+    /// Global variables
+    Gp,
+    /// Local variables of the current frame
+    Fp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "&&",
+            BinOp::Or => "||",
+        }
+    }
+
+    /// How tightly the operator binds, higher first
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 3,
+            BinOp::Add | BinOp::Sub => 4,
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Integer(i64),
+    Address(usize),
+    Str(String),
+    Register(Register),
+    Label(String),
+    Slot(Area, Box<Expr>),
+    /// Contents of the string at an address
+    Text(Box<Expr>),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn precedence(&self) -> u8 {
+        match *self {
+            Expr::Binary(op, ..) => op.precedence(),
+            Expr::Neg(..) | Expr::Not(..) => 6,
+            _ => 7,
+        }
+    }
+}
+
+/// Write `e`, in parentheses if it binds looser than `min`
+fn operand(f: &mut fmt::Formatter, e: &Expr, min: u8) -> fmt::Result {
+    if e.precedence() < min {
+        write!(f, "({})", e)
+    } else {
+        write!(f, "{}", e)
+    }
+}
+
+/// Prints the expression so it parses back to the same value
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Integer(i) => write!(f, "{}", i),
+            Expr::Address(a) => write!(f, "{}a", a),
+            Expr::Str(ref s) => write!(f, "{}", escape(s)),
+            Expr::Register(r) => write!(f, "{}", format!("{:?}", r).to_lowercase()),
+            Expr::Label(ref name) => write!(f, "{}", name),
+            Expr::Slot(area, ref index) => {
+                write!(f, "{}[{}]", format!("{:?}", area).to_lowercase(), index)
+            }
+            Expr::Text(ref addr) => write!(f, "str({})", addr),
+            Expr::Neg(ref e) => {
+                write!(f, "-")?;
+                operand(f, e, 6)
+            }
+            Expr::Not(ref e) => {
+                write!(f, "!")?;
+                operand(f, e, 6)
+            }
+            Expr::Binary(op, ref a, ref b) => {
+                // comparisons don't chain, the others are left associative
+                let left = if op.precedence() == 3 { 4 } else { op.precedence() };
+                operand(f, a, left)?;
+                write!(f, " {} ", op.symbol())?;
+                operand(f, b, op.precedence() + 1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Integer(i64),
+    Address(usize),
+    Str(String),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+/// Symbols, with the longer ones first so they are matched whole
+const SYMBOLS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", "[", "]",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+            let digits = &rest[..len];
+            let is_address = rest[len..].starts_with('a')
+                && !rest[len + 1..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+            if is_address {
+                let a = digits.parse().chain_err(|| format!("'{}a' is too large", digits))?;
+                tokens.push(Token::Address(a));
+                len + 1
+            } else {
+                let n = digits.parse().chain_err(|| format!("'{}' is too large", digits))?;
+                tokens.push(Token::Integer(n));
+                len
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '\''))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..len].to_string()));
+            len
+        } else if c == '"' {
+            let mut end = None;
+            let mut escaped = false;
+            for (i, c) in rest.char_indices().skip(1) {
+                match c {
+                    '"' if !escaped => {
+                        end = Some(i);
+                        break;
+                    }
+                    '\\' => escaped = !escaped,
+                    _ => escaped = false,
+                }
+            }
+            let end = end.ok_or("Unterminated string")?;
+            tokens.push(Token::Str(parser::unescape(&rest[1..end])?));
+            end + 1
+        } else {
+            match SYMBOLS.iter().find(|sym| rest.starts_with(*sym)) {
+                Some(sym) => {
+                    tokens.push(Token::Symbol(sym));
+                    sym.len()
+                }
+                None => bail!("Unexpected '{}'", c),
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens of an expression
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    /// Consume the first of `symbols` that is next
+    fn symbol(&mut self, symbols: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(&Token::Symbol(sym)) if symbols.contains(&sym) => {
+                self.pos += 1;
+                Some(sym)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<()> {
+        match self.symbol(&[symbol]) {
+            Some(_) => Ok(()),
+            None => bail!("Expected '{}'", symbol),
+        }
+    }
+
+    /// A left associative chain of binary operators
+    fn chain<F>(&mut self, ops: &[(&'static str, BinOp)], next: F) -> Result<Expr>
+    where
+        F: Fn(&mut Self) -> Result<Expr>,
+    {
+        let symbols: Vec<&'static str> = ops.iter().map(|&(s, _)| s).collect();
+        let mut lhs = next(self)?;
+        while let Some(sym) = self.symbol(&symbols) {
+            let op = ops.iter().find(|&&(s, _)| s == sym).expect("matched symbol").1;
+            let rhs = next(self)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        self.chain(&[("||", BinOp::Or)], Self::and)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        self.chain(&[("&&", BinOp::And)], Self::compare)
+    }
+
+    fn compare(&mut self) -> Result<Expr> {
+        let ops = [
+            ("==", BinOp::Eq),
+            ("!=", BinOp::Ne),
+            ("<=", BinOp::Le),
+            (">=", BinOp::Ge),
+            ("<", BinOp::Lt),
+            (">", BinOp::Gt),
+        ];
+        let lhs = self.sum()?;
+        let symbols: Vec<&'static str> = ops.iter().map(|&(s, _)| s).collect();
+        match self.symbol(&symbols) {
+            Some(sym) => {
+                let op = ops.iter().find(|&&(s, _)| s == sym).expect("matched symbol").1;
+                let rhs = self.sum()?;
+                Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)))
+            }
+            None => Ok(lhs),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        self.chain(&[("+", BinOp::Add), ("-", BinOp::Sub)], Self::product)
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        self.chain(
+            &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Mod)],
+            Self::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.symbol(&["-", "!"]) {
+            Some("-") => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(_) => Ok(Expr::Not(Box::new(self.unary()?))),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => bail!("Expected a value"),
+        };
+        self.pos += 1;
+        match token {
+            Token::Integer(i) => Ok(Expr::Integer(i)),
+            Token::Address(a) => Ok(Expr::Address(a)),
+            Token::Str(s) => Ok(Expr::Str(s)),
+            Token::Symbol("(") => {
+                let e = self.or()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            Token::Symbol(sym) => bail!("Unexpected '{}'", sym),
+            Token::Ident(name) => {
+                let area = match name.as_ref() {
+                    "stack" => Some(Area::Stack),
+                    "gp" => Some(Area::Gp),
+                    "fp" => Some(Area::Fp),
+                    _ => None,
+                };
+                if let Some(area) = area {
+                    if self.symbol(&["["]).is_some() {
+                        let index = self.or()?;
+                        self.expect("]")?;
+                        return Ok(Expr::Slot(area, Box::new(index)));
+                    }
+                }
+                if name == "str" && self.symbol(&["("]).is_some() {
+                    let addr = self.or()?;
+                    self.expect(")")?;
+                    return Ok(Expr::Text(Box::new(addr)));
+                }
+                Ok(match name.as_ref() {
+                    "sp" => Expr::Register(Register::Sp),
+                    "fp" => Expr::Register(Register::Fp),
+                    "pc" => Expr::Register(Register::Pc),
+                    "gp" => Expr::Register(Register::Gp),
+                    "steps" => Expr::Register(Register::Steps),
+                    "cost" => Expr::Register(Register::Cost),
+                    _ => Expr::Label(name),
+                })
+            }
+        }
+    }
+}
+
+/// Order two values of the same kind, or an integer and an address
+fn compare(a: &Value, b: &Value) -> Result<Ordering> {
+    match (a, b) {
+        (Value::Str(a), Value::Str(b)) => Ok(a.cmp(b)),
+        (&Value::Str(..), _) | (_, &Value::Str(..)) => {
+            bail!("Can't compare {} with {}", a, b)
+        }
+        _ => Ok(a.index()?.cmp(&b.index()?)),
+    }
+}
+
+fn arithmetic(op: BinOp, a: Value, b: Value) -> Result<Value> {
+    use self::Value::{Address, Integer};
+
+    let overflow = || format!("Overflow in {} {:?} {}", a, op, b);
+    Ok(match (op, &a, &b) {
+        (BinOp::Add, &Address(x), &Integer(y)) | (BinOp::Add, &Integer(y), &Address(x)) => {
+            Address((x as i64).checked_add(y).filter(|&r| r >= 0).ok_or_else(overflow)? as usize)
+        }
+        (BinOp::Sub, &Address(x), &Integer(y)) => {
+            Address((x as i64).checked_sub(y).filter(|&r| r >= 0).ok_or_else(overflow)? as usize)
+        }
+        (BinOp::Sub, &Address(x), &Address(y)) => Integer(x as i64 - y as i64),
+        (_, &Integer(x), &Integer(y)) => {
+            let res = match op {
+                BinOp::Add => x.checked_add(y),
+                BinOp::Sub => x.checked_sub(y),
+                BinOp::Mul => x.checked_mul(y),
+                BinOp::Div if y == 0 => bail!(ErrorKind::DivisionByZero),
+                BinOp::Div => x.checked_div(y),
+                BinOp::Mod if y == 0 => bail!(ErrorKind::DivisionByZero),
+                BinOp::Mod => x.checked_rem(y),
+                _ => unreachable!("not an arithmetic operator"),
+            };
+            Integer(res.ok_or_else(overflow)?)
+        }
+        _ => bail!("Can't apply {:?} to {} and {}", op, a, b),
+    })
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let e = parser.or()?;
+        match parser.peek() {
+            None => Ok(e),
+            Some(t) => bail!("Unexpected {:?} after the expression", t),
+        }
+    }
+
+    /// The absolute stack slot an `Expr::Slot` refers to, in a frame at
+    /// `fp`, which may be above the top of the stack
+    pub fn address(&self, m: &Machine, fp: usize) -> Result<usize> {
+        let (area, index) = match *self {
+            Expr::Slot(area, ref index) => (area, index.eval(m, fp)?.index()?),
+            _ => bail!("Not a stack slot. Use stack[i], gp[n] or fp[n]"),
+        };
+        let base = match area {
+            Area::Stack if index < 0 => m.sp() as i64,
+            Area::Stack => 0,
+            Area::Gp => m.gp as i64,
+            Area::Fp => fp as i64,
+        };
+        let slot = base + index;
+        if slot < 0 {
+            bail!("Slot {} is out of the stack", slot);
+        }
+        Ok(slot as usize)
+    }
+
+    /// The absolute stack slot an `Expr::Slot` refers to, in a frame at `fp`
+    pub fn slot(&self, m: &Machine, fp: usize) -> Result<usize> {
+        let slot = self.address(m, fp)?;
+        if slot >= m.sp() {
+            bail!("Slot {} is out of the stack (sp = {})", slot, m.sp());
+        }
+        Ok(slot)
+    }
+
+    /// The value of the expression, with `fp` as the frame pointer
+    pub fn eval(&self, m: &Machine, fp: usize) -> Result<Value> {
+        Ok(match *self {
+            Expr::Integer(i) => Value::Integer(i),
+            Expr::Address(a) => Value::Address(a),
+            Expr::Str(ref s) => Value::Str(s.clone()),
+            Expr::Register(r) => Value::Integer(match r {
+                Register::Sp => m.sp() as i64,
+                Register::Fp => fp as i64,
+                Register::Pc => m.pc as i64,
+                Register::Gp => m.gp as i64,
+                Register::Steps => m.steps as i64,
+                Register::Cost => m.cost as i64,
+            }),
+            Expr::Label(ref name) => match m.labels.get(name) {
+                Some(&pc) => Value::Integer(pc as i64),
+                None => bail!("Undefined label '{}'", name),
+            },
+            Expr::Slot(..) => Value::from(m.stack[self.slot(m, fp)?]),
+            Expr::Text(ref addr) => match addr.eval(m, fp)? {
+                Value::Address(a) if a < m.strings.len() => Value::Str(m.strings[a].clone()),
+                Value::Address(a) => bail!("No string at address {}", a),
+                v => bail!("{} is not an address", v),
+            },
+            Expr::Neg(ref e) => arithmetic(BinOp::Sub, Value::Integer(0), e.eval(m, fp)?)?,
+            Expr::Not(ref e) => Value::Integer(!e.eval(m, fp)?.truth()? as i64),
+            Expr::Binary(BinOp::And, ref a, ref b) => {
+                Value::Integer((a.eval(m, fp)?.truth()? && b.eval(m, fp)?.truth()?) as i64)
+            }
+            Expr::Binary(BinOp::Or, ref a, ref b) => {
+                Value::Integer((a.eval(m, fp)?.truth()? || b.eval(m, fp)?.truth()?) as i64)
+            }
+            Expr::Binary(op, ref a, ref b) => {
+                let (a, b) = (a.eval(m, fp)?, b.eval(m, fp)?);
+                let ordering = match op {
+                    BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                        compare(&a, &b)?
+                    }
+                    _ => return arithmetic(op, a, b),
+                };
+                let res = match op {
+                    BinOp::Eq => ordering == Ordering::Equal,
+                    BinOp::Ne => ordering != Ordering::Equal,
+                    BinOp::Lt => ordering == Ordering::Less,
+                    BinOp::Le => ordering != Ordering::Greater,
+                    BinOp::Gt => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                };
+                Value::Integer(res as i64)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expr, Value};
+    use vm::{Machine, Operand};
+
+    fn machine() -> Machine {
+        let mut m = Machine::default();
+        m.stack = vec![
+            Operand::Integer(3),
+            Operand::Integer(-7),
+            Operand::Address(0),
+            Operand::Integer(10),
+        ];
+        m.fp = 2;
+        m.strings = vec!["hello".to_string()];
+        m.labels.insert("loop".to_string(), 4);
+        m
+    }
+
+    fn eval(s: &str) -> Value {
+        let m = machine();
+        Expr::parse(s).unwrap().eval(&m, m.fp).unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("1 + 2 * 3 - -4"), Value::Integer(11));
+        assert_eq!(eval("(1 + 2) * 3 % 5"), Value::Integer(4));
+        assert_eq!(eval("sp - 1"), Value::Integer(3));
+        assert_eq!(eval("loop"), Value::Integer(4));
+        assert_eq!(eval("3a + 2"), Value::Address(5));
+        assert_eq!(eval("fp[0] == 0a"), Value::Integer(1));
+    }
+
+    #[test]
+    fn slots() {
+        assert_eq!(eval("gp[1]"), Value::Integer(-7));
+        assert_eq!(eval("stack[-1]"), Value::Integer(10));
+        assert_eq!(eval("fp[1]"), Value::Integer(10));
+        assert_eq!(eval("str(fp[0])"), Value::Str("hello".to_string()));
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(eval("gp[3] == 10 && stack[1] < 0"), Value::Integer(1));
+        assert_eq!(eval("str(stack[2]) == \"hello\" || 1 / 0"), Value::Integer(1));
+        assert_eq!(eval("!(gp[0] >= 3)"), Value::Integer(0));
+    }
+
+    #[test]
+    fn display() {
+        for s in &["1 - (2 - 3) * -(4 + 5)", "!(a == 1) || gp[sp - 1] != \"x\\n\" && str(fp[0]) < \"y\"", "(1 < 2) == 1"] {
+            let e = Expr::parse(s).unwrap();
+            assert_eq!(e.to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let m = machine();
+        for s in &["stack[4]", "1 / 0", "str(1)", "\"a\" < 1", "nolabel", "gp[", "1 2", "(1"] {
+            assert!(Expr::parse(s).and_then(|e| e.eval(&m, m.fp)).is_err(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Execution history of the debugger, to run the program backwards
+//!
+//! Each instruction run forward records a `Delta` with what it can change,
+//! which is enough to undo it. Only the last `MAX_DELTAS` are kept; older
+//! steps are reached by going back to a `Checkpoint` and running forward
+//! again. Checkpoints are taken at an interval that doubles whenever there
+//! are too many of them, so memory use grows slowly with the length of the run.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use errors::*;
+
+use commands::Status;
+use instructions::Instruction;
+use vm::{Access, Machine, Operand};
+
+/// Number of instructions that can be undone without a replay
+const MAX_DELTAS: usize = 1 << 16;
+
+/// Checkpoints kept before they are thinned out
+const MAX_CHECKPOINTS: usize = 64;
+
+/// Initial number of instructions between two checkpoints
+const INTERVAL: u64 = 1024;
+
+/// The state one instruction can change, as it was before it ran
+#[derive(Debug, Clone)]
+struct Delta {
+    pc: usize,
+    fp: usize,
+    gp: usize,
+    steps: u64,
+    cost: u64,
+    /// Values the instruction can pop, which start at `base`
+    base: usize,
+    top: Vec<Operand>,
+    /// A slot under them it writes, with its old value
+    written: Option<(usize, Operand)>,
+    call_depth: usize,
+    call_top: Option<(usize, usize)>,
+    /// Length of the string area
+    strings: usize,
+}
+
+impl Delta {
+    fn new(m: &Machine) -> Self {
+        let base = m.sp() - m.pops().min(m.sp());
+        let written = match m.access() {
+            Some(Access::Write(slot)) if slot < base => m.stack.get(slot).map(|&v| (slot, v)),
+            _ => None,
+        };
+        Delta {
+            pc: m.pc,
+            fp: m.fp,
+            gp: m.gp,
+            steps: m.steps,
+            cost: m.cost,
+            base,
+            top: m.stack[base..].to_vec(),
+            written,
+            call_depth: m.call_stack.len(),
+            call_top: m.call_stack.last().cloned(),
+            strings: m.strings.len(),
+        }
+    }
+
+    fn undo(self, m: &mut Machine) {
+        // the instruction only changed what it popped, what it pushed in
+        // their place and the slot it wrote
+        m.stack.truncate(self.base);
+        m.stack.extend(self.top);
+        if let Some((slot, v)) = self.written {
+            m.stack[slot] = v;
+        }
+        m.call_stack.truncate(self.call_depth.saturating_sub(1));
+        m.call_stack.extend(self.call_top);
+        m.strings.truncate(self.strings);
+        m.pc = self.pc;
+        m.fp = self.fp;
+        m.gp = self.gp;
+        m.steps = self.steps;
+        m.cost = self.cost;
+    }
+}
+
+/// The whole state of a run at some step
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    pc: usize,
+    fp: usize,
+    gp: usize,
+    steps: u64,
+    cost: u64,
+    stack: Vec<Operand>,
+    call_stack: Vec<(usize, usize)>,
+    strings: usize,
+}
+
+impl Checkpoint {
+    fn new(m: &Machine) -> Self {
+        Checkpoint {
+            pc: m.pc,
+            fp: m.fp,
+            gp: m.gp,
+            steps: m.steps,
+            cost: m.cost,
+            stack: m.stack.clone(),
+            call_stack: m.call_stack.clone(),
+            strings: m.strings.len(),
+        }
+    }
+
+    fn restore(&self, m: &mut Machine) {
+        m.pc = self.pc;
+        m.fp = self.fp;
+        m.gp = self.gp;
+        m.steps = self.steps;
+        m.cost = self.cost;
+        m.stack = self.stack.clone();
+        m.call_stack = self.call_stack.clone();
+        m.strings.truncate(self.strings);
+    }
+}
+
+/// What was run so far, by step number
+pub struct History {
+    /// The last instructions run, ending at the current step
+    deltas: VecDeque<Delta>,
+    /// Ordered by step, the first one at the start of the run
+    checkpoints: Vec<Checkpoint>,
+    interval: u64,
+    /// Lines given to `read`, by the step that read them
+    inputs: BTreeMap<u64, String>,
+    /// The furthest step reached, whose output was already shown
+    furthest: u64,
+}
+
+impl History {
+    pub fn new(m: &Machine) -> Self {
+        History {
+            deltas: VecDeque::new(),
+            checkpoints: vec![Checkpoint::new(m)],
+            interval: INTERVAL,
+            inputs: BTreeMap::new(),
+            furthest: m.steps,
+        }
+    }
+
+    /// The first step that can be gone back to
+    pub fn start(&self) -> u64 {
+        self.checkpoints[0].steps
+    }
+
+    /// Whether the next instruction was already run before going back
+    pub fn replaying(&self, m: &Machine) -> bool {
+        m.steps < self.furthest
+    }
+
+    fn checkpoint(&mut self, m: &Machine) {
+        if !m.steps.is_multiple_of(self.interval) {
+            return;
+        }
+        if let Err(i) = self.checkpoints.binary_search_by_key(&m.steps, |c| c.steps) {
+            self.checkpoints.insert(i, Checkpoint::new(m));
+        }
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.interval *= 2;
+            let (start, interval) = (self.start(), self.interval);
+            self.checkpoints
+                .retain(|c| c.steps == start || c.steps.is_multiple_of(interval));
+        }
+    }
+
+    /// Run the instruction at `pc`, recording how to undo it. The output
+    /// of steps that were already run is not shown again, and `read` gets
+    /// the same input as the first time.
+    pub fn forward(&mut self, m: &mut Machine) -> Result<Status> {
+        self.checkpoint(m);
+        let step = m.steps;
+        let read = m.get_instruction().ok() == Some(Instruction::Read);
+        if read {
+            m.input = self.inputs.get(&step).cloned();
+        }
+
+        self.deltas.push_back(Delta::new(m));
+        if self.deltas.len() > MAX_DELTAS {
+            self.deltas.pop_front();
+        }
+        m.quiet = self.replaying(m);
+        let status = m.run_instruction();
+        m.quiet = false;
+
+        if read && status.is_ok() {
+            let line = m.strings.last().cloned().unwrap_or_default();
+            self.inputs.entry(step).or_insert(line);
+        }
+        self.furthest = self.furthest.max(m.steps);
+        status
+    }
+
+    /// Go back to the state before `step`, which must not be after the
+    /// current one nor before the start
+    pub fn rewind(&mut self, m: &mut Machine, step: u64) -> Result<()> {
+        if step >= m.steps {
+            return Ok(());
+        }
+        if step < self.start() {
+            bail!("Step {} is before the start of the history", step);
+        }
+        if self.deltas.front().is_some_and(|d| d.steps <= step) {
+            while m.steps > step {
+                match self.deltas.pop_back() {
+                    Some(delta) => delta.undo(m),
+                    None => break,
+                }
+            }
+            return Ok(());
+        }
+
+        let i = match self.checkpoints.binary_search_by_key(&step, |c| c.steps) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        self.checkpoints[i].restore(m);
+        self.deltas.clear();
+        while m.steps < step {
+            self.forward(m)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::History;
+    use commands::Status;
+    use parser::parse;
+    use vm::Machine;
+
+    /// Everything a step can change
+    fn state(m: &Machine) -> String {
+        format!("{} {} {} {} {} {:?} {:?}", m.pc, m.fp, m.steps, m.cost, m.strings.len(),
+                m.stack, m.call_stack)
+    }
+
+    #[test]
+    fn rewind() {
+        let mut m = Machine::default();
+        m.load_program(parse("pushi 3\npushn 1\nstart\nloop: pusha dec\ncall\npushgp\npushi 1\n\
+             pushg 0\nstoren\npushi 0\npushg 0\ninf\njz end\njump loop\nend: stop\n\
+             dec: pushg 0\npushi -1\nadd\nstoreg 0\npushs \"s\"\nstoreg 1\nreturn\n").unwrap())
+            .unwrap();
+        let mut history = History::new(&m);
+        let mut states = vec![state(&m)];
+        while let Status::Success = history.forward(&mut m).unwrap() {
+            states.push(state(&m));
+        }
+
+        // back with the deltas, then forward again with them gone
+        for &step in &[40, 39, 12, 0] {
+            history.rewind(&mut m, step).unwrap();
+            assert_eq!(state(&m), states[step as usize]);
+        }
+        while m.steps < 30 {
+            history.forward(&mut m).unwrap();
+        }
+        history.deltas.clear();
+        for &step in &[29, 5, 1] {
+            history.rewind(&mut m, step).unwrap();
+            assert_eq!(state(&m), states[step as usize]);
+        }
+        assert!(history.rewind(&mut m, 0).is_ok());
+    }
+}
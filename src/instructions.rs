@@ -39,13 +39,31 @@ pub enum Instruction {
     Err(String),
 }
 
+/// Quote a string, escaping it so it reads back as the same value
+pub fn escape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Instruction::Pushi(ref val) => write!(f, "pushi {}", val),
             Instruction::Pushn(ref val) => write!(f, "pushn {}", val),
             Instruction::Pushg(ref val) => write!(f, "pushg {}", val),
-            Instruction::Pushs(ref val) => write!(f, "pushs {}", val),
+            Instruction::Pushs(ref val) => write!(f, "pushs {}", escape(val)),
             Instruction::Pusha(ref val) => write!(f, "pusha {}", val),
             Instruction::Pushgp => write!(f, "pushgp"),
             Instruction::Call => write!(f, "call"),
@@ -73,12 +91,50 @@ impl fmt::Display for Instruction {
             Instruction::Supeq => write!(f, "supeq"),
             Instruction::Jump(ref val) => write!(f, "jump {}", val),
             Instruction::Jz(ref val) => write!(f, "jz {}", val),
-            Instruction::Err(ref val) => write!(f, "err {}", val),
+            Instruction::Err(ref val) => write!(f, "err {}", escape(val)),
         }
     }
 }
 
 impl Instruction {
+    /// The instruction mnemonic
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Instruction::Pushi(..) => "pushi",
+            Instruction::Pushn(..) => "pushn",
+            Instruction::Pushg(..) => "pushg",
+            Instruction::Pushs(..) => "pushs",
+            Instruction::Pusha(..) => "pusha",
+            Instruction::Pushgp => "pushgp",
+            Instruction::Call => "call",
+            Instruction::Return => "return",
+            Instruction::Start => "start",
+            Instruction::Nop => "nop",
+            Instruction::Stop => "stop",
+            Instruction::Loadn => "loadn",
+            Instruction::Writei => "writei",
+            Instruction::Writes => "writes",
+            Instruction::Read => "read",
+            Instruction::Atoi => "atoi",
+            Instruction::Padd => "padd",
+            Instruction::Add => "add",
+            Instruction::Sub => "sub",
+            Instruction::Mul => "mul",
+            Instruction::Div => "div",
+            Instruction::Mod => "mod",
+            Instruction::Storeg(..) => "storeg",
+            Instruction::Storen => "storen",
+            Instruction::Equal => "equal",
+            Instruction::Inf => "inf",
+            Instruction::Infeq => "infeq",
+            Instruction::Sup => "sup",
+            Instruction::Supeq => "supeq",
+            Instruction::Jump(..) => "jump",
+            Instruction::Jz(..) => "jz",
+            Instruction::Err(..) => "err",
+        }
+    }
+
     pub fn write_ln(&self) {
         match *self {
             Instruction::Writei | Instruction::Writes => println!(),
@@ -0,0 +1,853 @@
+//! Compilation of hot code to native code with Cranelift
+//!
+//! Code that runs often is compiled one region at a time. A region is a
+//! group of basic blocks linked by their jumps, made only of instructions
+//! that do no I/O. The compiled code works on the operand stack of the
+//! `vm` in place, and returns to the interpreter before any instruction
+//! that could fail or that it does not know, so that the interpreter runs
+//! that instruction and reports any error exactly as it would without
+//! the JIT.
+
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use bytecode::Op;
+use errors::*;
+use vm::Operand;
+
+/// Times the code at an address runs in the interpreter before it is compiled
+const HOT: u32 = 64;
+/// Largest number of basic blocks in a region
+const MAX_BLOCKS: usize = 64;
+/// Largest `pushn` that is compiled
+const MAX_PUSHN: i32 = 16;
+/// Largest global variable index that is compiled
+const MAX_GLOBAL: usize = i32::MAX as usize;
+
+/// Tag of `Operand::Integer`, whose value is an `i32` at offset 4
+const INTEGER: i64 = 0;
+/// Tag of `Operand::Address`, whose value is an `u64` at offset 8
+const ADDRESS: i64 = 1;
+/// Size of an `Operand`, as a shift
+const OPERAND_SHIFT: i64 = 4;
+
+/// State shared with the compiled code
+#[repr(C)]
+struct Context {
+    stack: *mut Operand,
+    len: u64,
+    cap: u64,
+    gp: u64,
+    pc: u64,
+    steps: u64,
+    limit: u64,
+    cost: u64,
+}
+
+/// Offsets of the fields of `Context`
+const STACK: i32 = 0;
+const LEN: i32 = 8;
+const CAP: i32 = 16;
+const GP: i32 = 24;
+const PC: i32 = 32;
+const STEPS: i32 = 40;
+const LIMIT: i32 = 48;
+const COST: i32 = 56;
+
+type Code = unsafe extern "C" fn(*mut Context);
+
+/// What is known about the code starting at an address
+#[derive(Clone, Copy)]
+enum Entry {
+    /// Number of times it ran in the interpreter
+    Cold(u32),
+    /// Compiled code, and how much it can grow the stack in a basic block
+    Compiled(Code, usize),
+    /// There is nothing to compile
+    Never,
+}
+
+/// Registers of the `vm` read and updated by the compiled code
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub pc: usize,
+    pub gp: usize,
+    pub steps: u64,
+    pub cost: u64,
+}
+
+/// The compiler and the code compiled so far for a program
+pub struct Jit {
+    ops: Vec<Op>,
+    costs: Vec<u64>,
+    /// Addresses where a basic block starts
+    leaders: Vec<bool>,
+    entries: Vec<Entry>,
+    /// Created with the first compilation
+    module: Option<JITModule>,
+}
+
+/// A copy starts without compiled code
+impl Clone for Jit {
+    fn clone(&self) -> Self {
+        Jit::new(&self.ops, &self.costs)
+    }
+}
+
+/// Whether the JIT compiles the operation
+fn supported(op: Op) -> bool {
+    match op {
+        Op::Pushn(n) => n <= MAX_PUSHN,
+        Op::Pushg(n) | Op::Storeg(n) => n <= MAX_GLOBAL,
+        Op::Pushi(..)
+        | Op::Pushs(..)
+        | Op::Pusha(..)
+        | Op::Pushgp
+        | Op::Nop
+        | Op::Loadn
+        | Op::Storen
+        | Op::Padd
+        | Op::Add
+        | Op::Sub
+        | Op::Mul
+        | Op::Div
+        | Op::Mod
+        | Op::Equal
+        | Op::Inf
+        | Op::Infeq
+        | Op::Sup
+        | Op::Supeq
+        | Op::Jump(..)
+        | Op::Jz(..) => true,
+        _ => false,
+    }
+}
+
+/// Number of values an operation pops and pushes
+fn effect(op: Op) -> (i64, i64) {
+    match op {
+        Op::Pushn(n) => (0, i64::from(n.max(0))),
+        Op::Pushi(..) | Op::Pushs(..) | Op::Pusha(..) | Op::Pushgp | Op::Pushg(..) => (0, 1),
+        Op::Storeg(..) | Op::Jz(..) => (1, 0),
+        Op::Storen => (3, 0),
+        Op::Nop | Op::Jump(..) => (0, 0),
+        _ => (2, 1),
+    }
+}
+
+/// Stack depth needed to run `ops`, and how much they grow the stack at most
+fn depth(ops: &[Op]) -> (i64, i64) {
+    let (mut depth, mut need, mut growth) = (0, 0, 0);
+    for &op in ops {
+        let (pops, pushes) = effect(op);
+        need = need.max(pops - depth);
+        depth += pushes - pops;
+        growth = growth.max(depth);
+    }
+    (need, growth)
+}
+
+/// A group of basic blocks, as the addresses of their instructions
+struct Region {
+    blocks: Vec<(usize, usize)>,
+}
+
+fn module() -> Result<JITModule> {
+    let mut flags = settings::builder();
+    flags
+        .set("opt_level", "speed")
+        .map_err(|e| e.to_string())?;
+    let isa = cranelift_native::builder()?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| e.to_string())?;
+    Ok(JITModule::new(JITBuilder::with_isa(
+        isa,
+        default_libcall_names(),
+    )))
+}
+
+impl Jit {
+    pub fn new(ops: &[Op], costs: &[u64]) -> Jit {
+        let mut leaders = vec![false; ops.len() + 1];
+        leaders[0] = true;
+        for (i, &op) in ops.iter().enumerate() {
+            match op {
+                Op::Jump(addr) | Op::Jz(addr) | Op::Pusha(addr) if addr < ops.len() => {
+                    leaders[addr] = true
+                }
+                _ => {}
+            }
+            if !supported(op) || matches!(op, Op::Jump(..) | Op::Jz(..)) {
+                leaders[i + 1] = true;
+            }
+        }
+
+        Jit {
+            ops: ops.to_vec(),
+            costs: costs.to_vec(),
+            leaders,
+            entries: vec![Entry::Cold(0); ops.len()],
+            module: None,
+        }
+    }
+
+    /// Run the compiled code for the address in `regs`, once it is hot,
+    /// until it leaves its region or `limit` instructions have run.
+    /// Returns whether any instruction was run.
+    pub fn run(&mut self, regs: &mut Registers, stack: &mut Vec<Operand>, limit: u64) -> bool {
+        let pc = regs.pc;
+        let (code, growth) = match self.entries.get(pc).cloned() {
+            Some(Entry::Compiled(code, growth)) => (code, growth),
+            Some(Entry::Cold(n)) if n + 1 < HOT => {
+                self.entries[pc] = Entry::Cold(n + 1);
+                return false;
+            }
+            Some(Entry::Cold(..)) => {
+                // The interpreter runs the code when it can not be compiled
+                self.entries[pc] = match self.compile(pc) {
+                    Ok(Some((code, growth))) => Entry::Compiled(code, growth),
+                    _ => Entry::Never,
+                };
+                return false;
+            }
+            Some(Entry::Never) | None => return false,
+        };
+
+        stack.reserve(growth);
+        let mut ctx = Context {
+            stack: stack.as_mut_ptr(),
+            len: stack.len() as u64,
+            cap: stack.capacity() as u64,
+            gp: regs.gp as u64,
+            pc: pc as u64,
+            steps: regs.steps,
+            limit,
+            cost: regs.cost,
+        };
+
+        // The compiled code only writes below the capacity of the stack,
+        // and every value below the length it returns is initialized
+        unsafe {
+            code(&mut ctx);
+            stack.set_len(ctx.len as usize);
+        }
+
+        let ran = ctx.steps != regs.steps;
+        regs.pc = ctx.pc as usize;
+        regs.steps = ctx.steps;
+        regs.cost = ctx.cost;
+        ran
+    }
+
+    /// The basic blocks reachable from `pc`
+    fn region(&self, pc: usize) -> Region {
+        let mut blocks = Vec::new();
+        let mut seen = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(pc);
+
+        while let Some(start) = queue.pop_front() {
+            if seen.contains_key(&start)
+                || blocks.len() >= MAX_BLOCKS
+                || start >= self.ops.len()
+                || !supported(self.ops[start])
+            {
+                continue;
+            }
+
+            let mut end = start;
+            while end < self.ops.len()
+                && supported(self.ops[end])
+                && (end == start || !self.leaders[end])
+            {
+                end += 1;
+                if let Op::Jump(..) | Op::Jz(..) = self.ops[end - 1] {
+                    break;
+                }
+            }
+
+            match self.ops[end - 1] {
+                Op::Jump(addr) => queue.push_back(addr),
+                Op::Jz(addr) => {
+                    queue.push_back(addr);
+                    queue.push_back(end);
+                }
+                _ => queue.push_back(end),
+            }
+            seen.insert(start, blocks.len());
+            blocks.push((start, end));
+        }
+
+        Region { blocks }
+    }
+
+    /// Compile the region that starts at `pc`
+    fn compile(&mut self, pc: usize) -> Result<Option<(Code, usize)>> {
+        let region = self.region(pc);
+        if region.blocks.is_empty() {
+            return Ok(None);
+        }
+        let growth = region
+            .blocks
+            .iter()
+            .map(|&(start, end)| depth(&self.ops[start..end]).1)
+            .max()
+            .unwrap_or(0) as usize;
+
+        if self.module.is_none() {
+            self.module = Some(module()?);
+        }
+        let module = self.module.as_mut().expect("created above");
+        if module.target_config().pointer_type() != types::I64 {
+            bail!("The JIT needs a 64 bit target");
+        }
+
+        let mut ctx = module.make_context();
+        ctx.func
+            .signature
+            .params
+            .push(AbiParam::new(types::I64));
+        let mut func_ctx = FunctionBuilderContext::new();
+        {
+            let b = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+            Emitter::new(b, &self.ops, &self.costs).region(&region);
+        }
+
+        let id = module
+            .declare_anonymous_function(&ctx.func.signature)
+            .map_err(|e| e.to_string())?;
+        module
+            .define_function(id, &mut ctx)
+            .map_err(|e| e.to_string())?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().map_err(|e| e.to_string())?;
+
+        let code = module.get_finalized_function(id);
+        Ok(Some((
+            unsafe { mem::transmute::<*const u8, Code>(code) },
+            growth,
+        )))
+    }
+}
+
+/// A return to the interpreter, at `pc`, after running `steps` more
+/// instructions that cost `cost`
+struct Exit {
+    block: Block,
+    pc: usize,
+    steps: u64,
+    cost: u64,
+}
+
+/// Builds the code of a region
+struct Emitter<'a, 'b> {
+    b: FunctionBuilder<'b>,
+    ops: &'a [Op],
+    costs: &'a [u64],
+    ctx: Value,
+    base: Value,
+    gp: Value,
+    len: Variable,
+    steps: Variable,
+    cost: Variable,
+    /// First block of each basic block of the region
+    blocks: HashMap<usize, Block>,
+    exits: Vec<Exit>,
+    /// Where a failing check returns to the interpreter
+    at: (usize, u64, u64),
+}
+
+impl<'a, 'b> Emitter<'a, 'b> {
+    fn new(mut b: FunctionBuilder<'b>, ops: &'a [Op], costs: &'a [u64]) -> Self {
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+
+        let ctx = b.block_params(entry)[0];
+        let flags = MemFlags::trusted();
+        let base = b.ins().load(types::I64, flags, ctx, STACK);
+        let gp = b.ins().load(types::I64, flags, ctx, GP);
+
+        let (len, steps, cost) = (
+            Variable::from_u32(0),
+            Variable::from_u32(1),
+            Variable::from_u32(2),
+        );
+        for &(var, offset) in &[(len, LEN), (steps, STEPS), (cost, COST)] {
+            b.declare_var(var, types::I64);
+            let val = b.ins().load(types::I64, flags, ctx, offset);
+            b.def_var(var, val);
+        }
+
+        Emitter {
+            b,
+            ops,
+            costs,
+            ctx,
+            base,
+            gp,
+            len,
+            steps,
+            cost,
+            blocks: HashMap::new(),
+            exits: Vec::new(),
+            at: (0, 0, 0),
+        }
+    }
+
+    fn region(mut self, region: &Region) {
+        for &(start, _) in &region.blocks {
+            let block = self.b.create_block();
+            self.blocks.insert(start, block);
+        }
+        let first = self.blocks[&region.blocks[0].0];
+        self.b.ins().jump(first, &[]);
+
+        for &(start, end) in &region.blocks {
+            self.block(start, end);
+        }
+
+        for exit in mem::take(&mut self.exits) {
+            self.exit(exit);
+        }
+        self.b.seal_all_blocks();
+        self.b.finalize();
+    }
+
+    /// Return to the interpreter when `ok` is false
+    fn guard(&mut self, ok: Value) {
+        let (pc, steps, cost) = self.at;
+        let fail = self.b.create_block();
+        let cont = self.b.create_block();
+        self.b.ins().brif(ok, cont, &[], fail, &[]);
+        self.exits.push(Exit {
+            block: fail,
+            pc,
+            steps,
+            cost,
+        });
+        self.b.switch_to_block(cont);
+    }
+
+    fn exit(&mut self, exit: Exit) {
+        let flags = MemFlags::trusted();
+        self.b.switch_to_block(exit.block);
+
+        let len = self.b.use_var(self.len);
+        self.b.ins().store(flags, len, self.ctx, LEN);
+        let pc = self.b.ins().iconst(types::I64, exit.pc as i64);
+        self.b.ins().store(flags, pc, self.ctx, PC);
+        let steps = self.b.use_var(self.steps);
+        let steps = self.b.ins().iadd_imm(steps, exit.steps as i64);
+        self.b.ins().store(flags, steps, self.ctx, STEPS);
+        let cost = self.b.use_var(self.cost);
+        let cost = self.b.ins().iadd_imm(cost, exit.cost as i64);
+        self.b.ins().store(flags, cost, self.ctx, COST);
+        self.b.ins().return_(&[]);
+    }
+
+    /// The block that runs the code at `pc`, inside or outside of the region
+    fn target(&mut self, pc: usize) -> Block {
+        if let Some(&block) = self.blocks.get(&pc) {
+            return block;
+        }
+        let block = self.b.create_block();
+        self.exits.push(Exit {
+            block,
+            pc,
+            steps: 0,
+            cost: 0,
+        });
+        block
+    }
+
+    fn block(&mut self, start: usize, end: usize) {
+        let flags = MemFlags::trusted();
+        let ops = self.ops;
+        let ops = &ops[start..end];
+        let (need, growth) = depth(ops);
+
+        self.b.switch_to_block(self.blocks[&start]);
+        self.at = (start, 0, 0);
+
+        // The whole block fits in the stack and the instruction budget
+        let len = self.b.use_var(self.len);
+        let cap = self.b.ins().load(types::I64, flags, self.ctx, CAP);
+        let limit = self.b.ins().load(types::I64, flags, self.ctx, LIMIT);
+        let steps = self.b.use_var(self.steps);
+        let deep = self
+            .b
+            .ins()
+            .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, len, need);
+        let room = self.b.ins().iadd_imm(len, growth);
+        let room = self.b.ins().icmp(IntCC::UnsignedLessThanOrEqual, room, cap);
+        let next = self.b.ins().iadd_imm(steps, ops.len() as i64);
+        let budget = self.b.ins().icmp(IntCC::UnsignedLessThanOrEqual, next, limit);
+        let ok = self.b.ins().band(deep, room);
+        let ok = self.b.ins().band(ok, budget);
+        self.guard(ok);
+
+        let mut cost = 0;
+        for (i, &op) in ops.iter().enumerate() {
+            self.at = (start + i, i as u64, cost);
+            cost += self.costs[start + i];
+            self.op(op);
+        }
+
+        let total = (ops.len() as u64, cost);
+        match ops[ops.len() - 1] {
+            Op::Jump(addr) => {
+                self.finish(total);
+                let target = self.target(addr);
+                self.b.ins().jump(target, &[]);
+            }
+            Op::Jz(addr) => {
+                // `op` left the condition on the top of the stack
+                let len = self.b.use_var(self.len);
+                let top = self.slot_at(len, 0);
+                let cond = self.int(top);
+                self.finish(total);
+                let taken = self.target(addr);
+                let next = self.target(end);
+                self.b.ins().brif(cond, next, &[], taken, &[]);
+            }
+            _ => {
+                self.finish(total);
+                let next = self.target(end);
+                self.b.ins().jump(next, &[]);
+            }
+        }
+    }
+
+    /// Count the instructions of a block that ran
+    fn finish(&mut self, (steps, cost): (u64, u64)) {
+        let val = self.b.use_var(self.steps);
+        let val = self.b.ins().iadd_imm(val, steps as i64);
+        self.b.def_var(self.steps, val);
+        let val = self.b.use_var(self.cost);
+        let val = self.b.ins().iadd_imm(val, cost as i64);
+        self.b.def_var(self.cost, val);
+    }
+
+    /// Address of the stack value at `index`
+    fn slot(&mut self, index: Value) -> Value {
+        let offset = self.b.ins().ishl_imm(index, OPERAND_SHIFT);
+        self.b.ins().iadd(self.base, offset)
+    }
+
+    /// Address of the stack value at `len + delta`
+    fn slot_at(&mut self, len: Value, delta: i64) -> Value {
+        let index = self.b.ins().iadd_imm(len, delta);
+        self.slot(index)
+    }
+
+    fn grow(&mut self, delta: i64) {
+        let len = self.b.use_var(self.len);
+        let len = self.b.ins().iadd_imm(len, delta);
+        self.b.def_var(self.len, len);
+    }
+
+    fn tag(&mut self, slot: Value) -> Value {
+        self.b.ins().load(types::I32, MemFlags::trusted(), slot, 0)
+    }
+
+    fn int(&mut self, slot: Value) -> Value {
+        self.b.ins().load(types::I32, MemFlags::trusted(), slot, 4)
+    }
+
+    fn address(&mut self, slot: Value) -> Value {
+        self.b.ins().load(types::I64, MemFlags::trusted(), slot, 8)
+    }
+
+    fn is(&mut self, slot: Value, tag: i64) -> Value {
+        let val = self.tag(slot);
+        self.b.ins().icmp_imm(IntCC::Equal, val, tag)
+    }
+
+    fn put_int(&mut self, slot: Value, val: Value) {
+        let tag = self.b.ins().iconst(types::I32, INTEGER);
+        self.b.ins().store(MemFlags::trusted(), tag, slot, 0);
+        self.b.ins().store(MemFlags::trusted(), val, slot, 4);
+    }
+
+    fn put_address(&mut self, slot: Value, val: Value) {
+        let tag = self.b.ins().iconst(types::I32, ADDRESS);
+        self.b.ins().store(MemFlags::trusted(), tag, slot, 0);
+        self.b.ins().store(MemFlags::trusted(), val, slot, 8);
+    }
+
+    fn copy(&mut self, to: Value, from: Value) {
+        let flags = MemFlags::trusted();
+        let head = self.b.ins().load(types::I64, flags, from, 0);
+        let tail = self.b.ins().load(types::I64, flags, from, 8);
+        self.b.ins().store(flags, head, to, 0);
+        self.b.ins().store(flags, tail, to, 8);
+    }
+
+    /// The index computed by `Operand::add` from an integer and an address,
+    /// and whether it is below `bound`
+    fn index(&mut self, n: Value, a: Value, bound: Value) -> (Value, Value) {
+        let n = self.int(n);
+        let a = self.address(a);
+        let a = self.b.ins().ireduce(types::I32, a);
+        let (sum, overflow) = self.b.ins().sadd_overflow(a, n);
+        let index = self.b.ins().sextend(types::I64, sum);
+        let below = self.b.ins().icmp(IntCC::UnsignedLessThan, index, bound);
+        let ok = self.b.ins().bxor_imm(overflow, 1);
+        (index, self.b.ins().band(ok, below))
+    }
+
+    fn op(&mut self, op: Op) {
+        let len = self.b.use_var(self.len);
+        match op {
+            Op::Pushi(val) => {
+                let slot = self.slot_at(len, 0);
+                let val = self.b.ins().iconst(types::I32, i64::from(val));
+                self.put_int(slot, val);
+                self.grow(1);
+            }
+            Op::Pushn(n) => {
+                let zero = self.b.ins().iconst(types::I32, 0);
+                for i in 0..i64::from(n.max(0)) {
+                    let slot = self.slot_at(len, i);
+                    self.put_int(slot, zero);
+                }
+                self.grow(i64::from(n.max(0)));
+            }
+            Op::Pushgp => {
+                let slot = self.slot_at(len, 0);
+                self.put_address(slot, self.gp);
+                self.grow(1);
+            }
+            Op::Pushs(addr) | Op::Pusha(addr) => {
+                let slot = self.slot_at(len, 0);
+                let val = self.b.ins().iconst(types::I64, addr as i64);
+                self.put_address(slot, val);
+                self.grow(1);
+            }
+            Op::Pushg(n) => {
+                let index = self.b.ins().iadd_imm(self.gp, n as i64);
+                let ok = self.b.ins().icmp(IntCC::UnsignedLessThan, index, len);
+                self.guard(ok);
+                let from = self.slot(index);
+                let to = self.slot_at(len, 0);
+                self.copy(to, from);
+                self.grow(1);
+            }
+            Op::Storeg(n) => {
+                let index = self.b.ins().iadd_imm(self.gp, n as i64);
+                let bound = self.b.ins().iadd_imm(len, -1);
+                let ok = self.b.ins().icmp(IntCC::UnsignedLessThan, index, bound);
+                self.guard(ok);
+                let to = self.slot(index);
+                let from = self.slot_at(len, -1);
+                self.copy(to, from);
+                self.grow(-1);
+            }
+            Op::Loadn => {
+                let (n, a) = (self.slot_at(len, -1), self.slot_at(len, -2));
+                let bound = self.b.ins().iadd_imm(len, -2);
+                let (index, ok) = self.index(n, a, bound);
+                let n_int = self.is(n, INTEGER);
+                let a_address = self.is(a, ADDRESS);
+                let ok = self.b.ins().band(ok, n_int);
+                let ok = self.b.ins().band(ok, a_address);
+                self.guard(ok);
+                let from = self.slot(index);
+                self.copy(a, from);
+                self.grow(-1);
+            }
+            Op::Storen => {
+                let v = self.slot_at(len, -1);
+                let (n, a) = (self.slot_at(len, -2), self.slot_at(len, -3));
+                let bound = self.b.ins().iadd_imm(len, -3);
+                let (index, ok) = self.index(n, a, bound);
+                let n_int = self.is(n, INTEGER);
+                let a_address = self.is(a, ADDRESS);
+                let ok = self.b.ins().band(ok, n_int);
+                let ok = self.b.ins().band(ok, a_address);
+                self.guard(ok);
+                let to = self.slot(index);
+                self.copy(to, v);
+                self.grow(-3);
+            }
+            Op::Add | Op::Padd => {
+                // An integer added to an integer or to an address, which
+                // keeps the tag of the address
+                let (n, m) = (self.slot_at(len, -1), self.slot_at(len, -2));
+                let n_int = self.is(n, INTEGER);
+                let m_tag = self.tag(m);
+                let m_int = self.b.ins().icmp_imm(IntCC::Equal, m_tag, INTEGER);
+                let m_address = self.b.ins().icmp_imm(IntCC::Equal, m_tag, ADDRESS);
+                let m_int_val = self.int(m);
+                let m_address_val = self.address(m);
+                let m_address_val = self.b.ins().ireduce(types::I32, m_address_val);
+                let m_val = self.b.ins().select(m_int, m_int_val, m_address_val);
+                let n_val = self.int(n);
+                let (sum, overflow) = self.b.ins().sadd_overflow(m_val, n_val);
+
+                let ok = self.b.ins().bor(m_int, m_address);
+                let ok = self.b.ins().band(ok, n_int);
+                let no_overflow = self.b.ins().bxor_imm(overflow, 1);
+                let ok = self.b.ins().band(ok, no_overflow);
+                self.guard(ok);
+
+                let flags = MemFlags::trusted();
+                let wide = self.b.ins().sextend(types::I64, sum);
+                self.b.ins().store(flags, m_tag, m, 0);
+                self.b.ins().store(flags, sum, m, 4);
+                self.b.ins().store(flags, wide, m, 8);
+                self.grow(-1);
+            }
+            Op::Sub | Op::Mul | Op::Div | Op::Mod => {
+                let (n, m) = (self.slot_at(len, -1), self.slot_at(len, -2));
+                let n_int = self.is(n, INTEGER);
+                let m_int = self.is(m, INTEGER);
+                let ok = self.b.ins().band(n_int, m_int);
+                let (n_val, m_val) = (self.int(n), self.int(m));
+
+                let res = match op {
+                    // `n - m`, as in `Operand::sub`
+                    Op::Sub | Op::Mul => {
+                        let (res, overflow) = if let Op::Sub = op {
+                            self.b.ins().ssub_overflow(n_val, m_val)
+                        } else {
+                            self.b.ins().smul_overflow(m_val, n_val)
+                        };
+                        let no_overflow = self.b.ins().bxor_imm(overflow, 1);
+                        let ok = self.b.ins().band(ok, no_overflow);
+                        self.guard(ok);
+                        res
+                    }
+                    _ => {
+                        let nonzero = self.b.ins().icmp_imm(IntCC::NotEqual, n_val, 0);
+                        let min = self.b.ins().icmp_imm(IntCC::Equal, m_val, i64::from(i32::MIN));
+                        let minus_one = self.b.ins().icmp_imm(IntCC::Equal, n_val, -1);
+                        let overflow = self.b.ins().band(min, minus_one);
+                        let no_overflow = self.b.ins().bxor_imm(overflow, 1);
+                        let ok = self.b.ins().band(ok, nonzero);
+                        let ok = self.b.ins().band(ok, no_overflow);
+                        self.guard(ok);
+                        if let Op::Div = op {
+                            self.b.ins().sdiv(m_val, n_val)
+                        } else {
+                            self.b.ins().srem(m_val, n_val)
+                        }
+                    }
+                };
+                self.put_int(m, res);
+                self.grow(-1);
+            }
+            Op::Equal | Op::Inf | Op::Infeq | Op::Sup | Op::Supeq => {
+                let cc = match op {
+                    Op::Equal => IntCC::Equal,
+                    Op::Inf => IntCC::SignedLessThan,
+                    Op::Infeq => IntCC::SignedLessThanOrEqual,
+                    Op::Sup => IntCC::SignedGreaterThan,
+                    _ => IntCC::SignedGreaterThanOrEqual,
+                };
+                let (n, m) = (self.slot_at(len, -1), self.slot_at(len, -2));
+                let n_int = self.is(n, INTEGER);
+                let m_int = self.is(m, INTEGER);
+                let ok = self.b.ins().band(n_int, m_int);
+                self.guard(ok);
+                let (n_val, m_val) = (self.int(n), self.int(m));
+                let res = self.b.ins().icmp(cc, m_val, n_val);
+                let res = self.b.ins().uextend(types::I32, res);
+                self.put_int(m, res);
+                self.grow(-1);
+            }
+            Op::Jz(..) => {
+                // Only `0` and `1` are conditions
+                let top = self.slot_at(len, -1);
+                let int = self.is(top, INTEGER);
+                let val = self.int(top);
+                let bool = self.b.ins().icmp_imm(IntCC::UnsignedLessThanOrEqual, val, 1);
+                let ok = self.b.ins().band(int, bool);
+                self.guard(ok);
+                self.grow(-1);
+            }
+            Op::Nop | Op::Jump(..) => {}
+            _ => unreachable!("{:?} is not compiled", op),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Jit, Registers};
+    use bytecode::compile;
+    use parser::parse;
+    use vm::Operand;
+
+    /// Run the JIT from the start of `source` until it returns
+    fn run(source: &str) -> (Registers, Vec<Operand>) {
+        let ops = compile(&parse(source).unwrap()).unwrap().ops;
+        let mut jit = Jit::new(&ops, &vec![1; ops.len()]);
+        let mut regs = Registers {
+            pc: 0,
+            gp: 0,
+            steps: 0,
+            cost: 0,
+        };
+        let mut stack = Vec::new();
+        while !jit.run(&mut regs, &mut stack, u64::MAX) {}
+        (regs, stack)
+    }
+
+    fn ints(stack: &[Operand]) -> Vec<i32> {
+        stack
+            .iter()
+            .map(|op| match *op {
+                Operand::Integer(i) => i,
+                Operand::Address(a) => panic!("address {}", a),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn operand_layout() {
+        let ops = [Operand::Integer(-7), Operand::Address(42)];
+        let p = ops.as_ptr() as *const u8;
+        unsafe {
+            assert_eq!(*(p as *const u32), super::INTEGER as u32);
+            assert_eq!(*(p.add(4) as *const i32), -7);
+            assert_eq!(*(p.add(16) as *const u32), super::ADDRESS as u32);
+            assert_eq!(*(p.add(24) as *const u64), 42);
+        }
+    }
+
+    #[test]
+    fn loop_sum() {
+        // the sum of 1 to 10 in the second global, with the first as counter
+        let (regs, stack) = run("pushi 0\npushi 0\nloop:\npushg 0\npushi 10\ninf\njz end\n\
+             pushg 0\npushi 1\nadd\nstoreg 0\npushg 1\npushg 0\nadd\nstoreg 1\njump loop\nend:\n");
+        assert_eq!(ints(&stack), [10, 55]);
+        assert_eq!(regs.pc, 15);
+        assert_eq!(regs.steps, 2 + 10 * 13 + 4);
+        assert_eq!(regs.cost, regs.steps);
+    }
+
+    #[test]
+    fn returns_before_errors() {
+        let (regs, stack) = run("pushi 1\npushi 0\ndiv\n");
+        assert_eq!(ints(&stack), [1, 0]);
+        assert_eq!(regs.pc, 2);
+        assert_eq!(regs.steps, 2);
+    }
+
+    #[test]
+    fn returns_before_io() {
+        let (regs, stack) = run("pushi 4\npushi 3\nsub\nwritei\n");
+        assert_eq!(ints(&stack), [-1]);
+        assert_eq!(regs.pc, 3);
+    }
+}
@@ -11,32 +11,128 @@
 //! pl-vm --debug <file>
 //! ```
 //!
-
-#![recursion_limit = "512"]
-
-#[macro_use]
-extern crate pest;
+//! Running debug commands from a file, and exiting after them:
+//!
+//! ```
+//! pl-vm --debug <file> -x <commands> --batch
+//! ```
+//!
+//! Reporting errors as `json` for tools:
+//!
+//! ```
+//! pl-vm --error-format=json <file>
+//! ```
+//!
+//! Limiting a run to a number of instructions or seconds:
+//!
+//! ```
+//! pl-vm --max-steps 100000 --timeout 5 <file>
+//! ```
+//!
+//! Printing execution statistics at the end of the run:
+//!
+//! ```
+//! pl-vm --stats <file>
+//! ```
+//!
+//! Scoring a run with the default instruction costs, or those in a file:
+//!
+//! ```
+//! pl-vm --print-cost <file>
+//! pl-vm --costs <costs> <file>
+//! ```
+//!
+//! Converting between `.vm` source and `.vmb` binary files:
+//!
+//! ```
+//! pl-vm assemble <file> -o <file.vmb>
+//! pl-vm disasm <file.vmb>
+//! ```
+//!
+//! Optimizing a program, explaining each change:
+//!
+//! ```
+//! pl-vm optimize --explain <file> -o <file.opt.vm>
+//! ```
+//!
+//! Translating a program to C:
+//!
+//! ```
+//! pl-vm to-c <file> > <file.c>
+//! ```
+//!
+//! Compiling hot code to native code needs the `jit` feature:
+//!
+//! ```
+//! cargo build --release --features jit
+//! ```
+//!
 
 extern crate ansi_term;
 extern crate clap;
 #[macro_use]
 extern crate error_chain;
+extern crate rustyline;
+
+#[cfg(feature = "jit")]
+extern crate cranelift_codegen;
+#[cfg(feature = "jit")]
+extern crate cranelift_frontend;
+#[cfg(feature = "jit")]
+extern crate cranelift_jit;
+#[cfg(feature = "jit")]
+extern crate cranelift_module;
+#[cfg(feature = "jit")]
+extern crate cranelift_native;
 
 mod vm;
 mod instructions;
 mod commands;
+mod debugger;
+mod expr;
+mod history;
+mod prompt;
 mod parser;
+mod program;
+mod stats;
+mod cost;
+mod bytecode;
+mod vmb;
+mod optimizer;
+mod to_c;
+#[cfg(feature = "jit")]
+mod jit;
 
-use vm::Mode;
-use clap::{App, Arg};
+use std::fs::File;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use cost::CostTable;
+use program::{Encoding, Program};
+use vm::{Mode, Options};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
 /// Error handling
 mod errors {
+    use std::fmt;
 
     use ansi_term::Color::Red;
 
+    use parser::Diagnostic;
+    use program::Span;
+    use vm::Operand;
+
     error_chain!{
         errors {
+            /// Triggered when the source file does not follow the `vm` syntax
+            Syntax (span: Span, s: String) {
+                display("{} {} => {}", Red.paint("Syntax Error:"), span, s)
+            }
+            /// Triggered when the source file has mistakes, with every one found
+            Diagnostics (d: Vec<Diagnostic>) {
+                display("{}", d.iter().map(|d| d.to_string()).collect::<Vec<_>>().join("\n"))
+            }
             /// Triggered when the value(s) on the stack are not of the expected nature
             IllegalOperand (s: String) {
                 display("{} {}", Red.paint("Illegal Operand:"), s)
@@ -63,9 +159,18 @@ mod errors {
             Anomaly (s: String) {
                 display("{} {}", Red.paint("Anomaly:"), s)
             }
+            /// Triggered when a run goes over its instruction or time limit
+            BudgetExhausted (s: String) {
+                display("{} {}", Red.paint("Budget Exhausted:"), s)
+            }
         }
     }
 
+    /// Exit code for a failed run
+    pub const EXIT_FAILURE: i32 = 1;
+    /// Exit code for a run stopped by `--max-steps` or `--timeout`
+    pub const EXIT_BUDGET: i32 = 124;
+
     /// Print the error chain in oneline
     pub fn print_errs(e: &Error) {
         print!("\t{}. ", e);
@@ -75,11 +180,423 @@ mod errors {
         println!();
     }
 
-    /// Print a multiline error chain
+    /// Print a multiline error chain, with each mistake of a source file on its own
     pub fn print_errors(e: &Error) {
-        println!("\n{}", e.to_string());
-        for e in e.iter().skip(1) {
-            println!("{} {}", Red.paint("caused by:"), e);
+        for line in lines(e) {
+            println!("{}", line);
+        }
+    }
+
+    /// The lines of a multiline error chain
+    fn lines(e: &Error) -> Vec<String> {
+        let kinds = kinds(e);
+        let mut res = Vec::new();
+        for kind in &kinds {
+            match **kind {
+                // The mistakes of a file are errors of their own, not causes of one another
+                ErrorKind::Diagnostics(ref d) => res.extend(d.iter().map(|d| format!("\n{}", d))),
+                ref kind => res.push(cause(res.is_empty(), kind)),
+            }
+        }
+        res.extend(e.iter().skip(kinds.len()).map(|e| cause(false, e)));
+        res
+    }
+
+    /// The first error of a chain, or the cause of the errors before it
+    fn cause<E: fmt::Display + ?Sized>(first: bool, e: &E) -> String {
+        if first {
+            format!("\n{}", e)
+        } else {
+            format!("{} {}", Red.paint("caused by:"), e)
+        }
+    }
+
+    /// How errors are reported to the user
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Format {
+        /// Colored text meant to be read by people
+        Human,
+        /// A single `json` object per error, meant for tools
+        Json,
+    }
+
+    /// The state of the `vm` at the moment execution failed
+    #[derive(Debug, Clone)]
+    pub struct Snapshot {
+        pub pc: usize,
+        pub span: Option<Span>,
+        pub stack: Vec<Operand>,
+    }
+
+    /// An error together with the machine state, when there is one
+    #[derive(Debug)]
+    pub struct Failure {
+        pub error: Error,
+        pub snapshot: Option<Box<Snapshot>>,
+    }
+
+    impl From<Error> for Failure {
+        fn from(e: Error) -> Self {
+            Failure {
+                error: e,
+                snapshot: None,
+            }
+        }
+    }
+
+    /// The errors of the chain that are defined in this module
+    fn kinds(e: &Error) -> Vec<&ErrorKind> {
+        let mut res = vec![e.kind()];
+        let mut next = e.1.next_error.as_ref();
+        while let Some(err) = next.and_then(|n| n.downcast_ref::<Error>()) {
+            res.push(err.kind());
+            next = err.1.next_error.as_ref();
+        }
+        res
+    }
+
+    /// The name and uncolored message of an error
+    fn describe(kind: &ErrorKind) -> (&'static str, String) {
+        match *kind {
+            ErrorKind::Msg(ref s) => ("Message", s.clone()),
+            ErrorKind::Syntax(ref span, ref s) => ("Syntax", format!("{} => {}", span, s)),
+            ErrorKind::Diagnostics(ref d) => {
+                let messages: Vec<String> = d.iter().map(|d| format!("{} => {}", d.span, d.message)).collect();
+                ("Syntax", messages.join("\n"))
+            }
+            ErrorKind::IllegalOperand(ref s) => ("IllegalOperand", s.clone()),
+            ErrorKind::SegmentationFault(ref s) => ("SegmentationFault", s.clone()),
+            ErrorKind::StackOverflow => ("StackOverflow", "Stack Overflow".to_string()),
+            ErrorKind::DivisionByZero => ("DivisionByZero", "Division By Zero".to_string()),
+            ErrorKind::Error(ref s) => ("Error", s.clone()),
+            ErrorKind::Anomaly(ref s) => ("Anomaly", s.clone()),
+            ErrorKind::BudgetExhausted(ref s) => ("BudgetExhausted", s.clone()),
+        }
+    }
+
+    /// The process exit code for an error
+    pub fn exit_code(e: &Error) -> i32 {
+        match *e.kind() {
+            ErrorKind::BudgetExhausted(..) => EXIT_BUDGET,
+            _ => EXIT_FAILURE,
+        }
+    }
+
+    /// Print where the machine stopped
+    fn print_snapshot(s: &Snapshot) {
+        match s.span {
+            Some(ref span) => println!("{} pc = {} at {}", Red.paint("stopped:"), s.pc, span),
+            None => println!("{} pc = {}", Red.paint("stopped:"), s.pc),
+        }
+        let stack: Vec<String> = s.stack.iter().map(|o| o.to_string()).collect();
+        println!("{} [{}]", Red.paint("stack:"), stack.join(", "));
+    }
+
+    /// Escape a string as a `json` string literal
+    pub fn json_str(s: &str) -> String {
+        let mut res = String::with_capacity(s.len() + 2);
+        res.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => res.push_str("\\\""),
+                '\\' => res.push_str("\\\\"),
+                '\n' => res.push_str("\\n"),
+                '\r' => res.push_str("\\r"),
+                '\t' => res.push_str("\\t"),
+                c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+                c => res.push(c),
+            }
+        }
+        res.push('"');
+        res
+    }
+
+    fn json_span(span: Option<&Span>) -> String {
+        match span {
+            Some(s) => format!(
+                "{{\"line\":{},\"col\":{},\"end_line\":{},\"end_col\":{}}}",
+                s.line, s.col, s.end_line, s.end_col
+            ),
+            None => "null".to_string(),
+        }
+    }
+
+    /// An error as `json` objects, one for each mistake of a source file
+    fn json(f: &Failure) -> Vec<String> {
+        let kinds = kinds(&f.error);
+
+        // The most specific error of the chain names the failure
+        let index = kinds
+            .iter()
+            .rposition(|k| !matches!(**k, ErrorKind::Msg(..)))
+            .unwrap_or(0);
+        let kind = kinds[index];
+        let (name, message) = describe(kind);
+
+        // A source file with several mistakes gets an object for each one
+        let failures: Vec<(String, Option<&Span>)> = match *kind {
+            ErrorKind::Syntax(ref span, _) => vec![(message, Some(span))],
+            ErrorKind::Diagnostics(ref d) => d
+                .iter()
+                .map(|d| (format!("{} => {}", d.span, d.message), Some(&d.span)))
+                .collect(),
+            _ => vec![(message, f.snapshot.as_ref().and_then(|s| s.span.as_ref()))],
+        };
+
+        // The rest of the chain, as errors from other crates are only known by their message
+        let mut causes: Vec<String> = kinds.iter().map(|k| json_str(&describe(k).1)).collect();
+        causes.extend(
+            f.error
+                .iter()
+                .skip(kinds.len())
+                .map(|e| json_str(&e.to_string())),
+        );
+        causes.remove(index);
+
+        let (pc, stack) = match f.snapshot {
+            Some(ref s) => {
+                let stack: Vec<String> = s.stack.iter().map(|o| o.to_json()).collect();
+                (s.pc.to_string(), format!("[{}]", stack.join(",")))
+            }
+            None => ("null".to_string(), "null".to_string()),
+        };
+
+        failures
+            .into_iter()
+            .map(|(message, span)| {
+                format!(
+                    "{{\"kind\":{},\"message\":{},\"pc\":{},\"span\":{},\"stack\":{},\"causes\":[{}]}}",
+                    json_str(name),
+                    json_str(&message),
+                    pc,
+                    json_span(span),
+                    stack,
+                    causes.join(",")
+                )
+            })
+            .collect()
+    }
+
+    /// Print an error as single line `json` objects to `stderr`
+    pub fn print_json(f: &Failure) {
+        for line in json(f) {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// Report a failure in the requested format
+    pub fn report(f: &Failure, format: Format) {
+        match format {
+            Format::Human => {
+                print_errors(&f.error);
+                // Show where a runaway program was when it got stopped
+                if let (&ErrorKind::BudgetExhausted(..), Some(s)) = (f.error.kind(), f.snapshot.as_ref()) {
+                    print_snapshot(s);
+                }
+            }
+            Format::Json => print_json(f),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{json, json_str, lines, Error, ErrorKind, Failure, ResultExt, Snapshot};
+        use parser::parse;
+        use vm::Operand;
+
+        #[test]
+        fn escapes() {
+            assert_eq!(json_str("a\"b\\c"), r#""a\"b\\c""#);
+            assert_eq!(json_str("\n\r\t\u{1}é"), r#""\n\r\t\u0001é""#);
+        }
+
+        #[test]
+        fn runtime_error() {
+            let f = Failure {
+                error: ErrorKind::BudgetExhausted("step limit of 100 instructions reached".to_string()).into(),
+                snapshot: Some(Box::new(Snapshot { pc: 5, span: None, stack: vec![Operand::Integer(20)] })),
+            };
+            assert_eq!(json(&f), [concat!(
+                r#"{"kind":"BudgetExhausted","message":"step limit of 100 instructions reached","#,
+                r#""pc":5,"span":null,"stack":[{"type":"integer","value":20}],"causes":[]}"#
+            )]);
+        }
+
+        #[test]
+        fn syntax_errors() {
+            let error = parse("start\nstroeg 1\npushi x\n")
+                .chain_err(|| "Unable to Parse file 'a.vm'")
+                .unwrap_err();
+            let lines = json(&Failure::from(error));
+            assert_eq!(lines, [
+                concat!(
+                    r#"{"kind":"Syntax","message":"line(2), col(1) => unknown instruction `stroeg`","#,
+                    r#""pc":null,"span":{"line":2,"col":1,"end_line":2,"end_col":7},"stack":null,"#,
+                    r#""causes":["Unable to Parse file 'a.vm'"]}"#
+                ),
+                concat!(
+                    r#"{"kind":"Syntax","message":"line(3), col(7) => unexpected `x`","#,
+                    r#""pc":null,"span":{"line":3,"col":7,"end_line":3,"end_col":8},"stack":null,"#,
+                    r#""causes":["Unable to Parse file 'a.vm'"]}"#
+                ),
+            ]);
+        }
+
+        #[test]
+        fn diagnostics() {
+            let error = parse("start\nstroeg 1\npushi x\n")
+                .chain_err(|| "Unable to Parse file 'a.vm'")
+                .unwrap_err();
+            let lines = lines(&error);
+            assert_eq!(lines.len(), 3);
+            assert_eq!(lines[0], "\nUnable to Parse file 'a.vm'");
+            assert!(lines[1].starts_with("\n") && lines[1].contains("unknown instruction `stroeg`"));
+            assert!(lines[2].starts_with("\n") && lines[2].contains("unexpected `x`"));
+        }
+
+        #[test]
+        fn causes() {
+            let error = Err::<(), Error>("Invalid stack".into())
+                .chain_err(|| "Unable to Run file 'a.vm'")
+                .unwrap_err();
+            let lines = lines(&error);
+            assert_eq!(lines[0], "\nUnable to Run file 'a.vm'");
+            assert!(lines[1].contains("caused by:") && lines[1].ends_with(" Invalid stack"));
+        }
+    }
+}
+
+/// Read the execution limits from the command line
+fn options(matches: &ArgMatches) -> errors::Result<Options> {
+    let costs = match matches.value_of("costs") {
+        Some(path) => Some(CostTable::load(path)?),
+        None => None,
+    };
+
+    Ok(Options {
+        max_steps: matches
+            .value_of("max-steps")
+            .map(|n| n.parse().expect("validated by clap")),
+        timeout: matches
+            .value_of("timeout")
+            .map(|s| Duration::from_secs_f64(s.parse().expect("validated by clap"))),
+        stats: matches.is_present("stats"),
+        print_cost: matches.is_present("print-cost") || costs.is_some(),
+        costs,
+        encoding: encoding(matches),
+        commands: matches
+            .values_of("command")
+            .map(|files| files.map(String::from).collect())
+            .unwrap_or_default(),
+        batch: matches.is_present("batch"),
+    })
+}
+
+fn is_number(s: String) -> Result<(), String> {
+    s.parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("'{}' is not a valid number", s))
+}
+
+/// Read the source encoding from the command line
+fn encoding(matches: &ArgMatches) -> Encoding {
+    match matches.value_of("encoding") {
+        Some("utf-8") => Encoding::Utf8,
+        Some("latin-1") => Encoding::Latin1,
+        _ => Encoding::Auto,
+    }
+}
+
+/// Run the program in the `input` file
+fn run(matches: &ArgMatches) -> Result<(), errors::Failure> {
+    let mode = if matches.is_present("debug") || matches.is_present("command") {
+        Mode::Debug
+    } else {
+        Mode::Running
+    };
+
+    let file = matches.value_of("input").expect("required by clap");
+    let options = options(matches)?;
+
+    // a batch debug session ends with the status of the program
+    let code = vm::start(file, mode, &options)?;
+    if code != 0 {
+        process::exit(code);
+    }
+    Ok(())
+}
+
+/// Write the program in the `input` file as a `.vmb` file
+fn assemble(matches: &ArgMatches) -> errors::Result<()> {
+    use errors::ResultExt;
+
+    let input = matches.value_of("input").expect("required by clap");
+    let output = match matches.value_of("output") {
+        Some(output) => Path::new(output).to_path_buf(),
+        None => Path::new(input).with_extension("vmb"),
+    };
+
+    let program = Program::load(input, encoding(matches))?;
+    let file = File::create(&output)
+        .chain_err(|| format!("Failed to create file '{}'", output.display()))?;
+    vmb::write(&program, !matches.is_present("strip"), file)
+        .chain_err(|| format!("Unable to Write file '{}'", output.display()))
+}
+
+/// Print the program in the `input` file as `.vm` source
+fn disasm(matches: &ArgMatches) -> errors::Result<()> {
+    let input = matches.value_of("input").expect("required by clap");
+    print!("{}", Program::load(input, encoding(matches))?);
+    Ok(())
+}
+
+/// Write the optimized program in the `input` file as `.vm` source
+fn optimize(matches: &ArgMatches) -> errors::Result<()> {
+    use errors::ResultExt;
+    use std::io::Write;
+
+    let input = matches.value_of("input").expect("required by clap");
+    let (program, rewrites) = optimizer::optimize(&Program::load(input, encoding(matches))?);
+
+    if matches.is_present("explain") {
+        for rewrite in &rewrites {
+            eprintln!("{}", rewrite);
+        }
+    }
+
+    match matches.value_of("output") {
+        Some(output) => {
+            let mut file = File::create(output)
+                .chain_err(|| format!("Failed to create file '{}'", output))?;
+            write!(file, "{}", program).chain_err(|| format!("Unable to Write file '{}'", output))
+        }
+        None => {
+            print!("{}", program);
+            Ok(())
+        }
+    }
+}
+
+/// Write the program in the `input` file as a C program
+fn to_c(matches: &ArgMatches) -> errors::Result<()> {
+    use errors::ResultExt;
+    use std::io::Write;
+
+    let input = matches.value_of("input").expect("required by clap");
+    let program = Program::load(input, encoding(matches))?;
+    let source = to_c::translate(&program)
+        .chain_err(|| format!("Unable to Parse file '{}'", input))?;
+
+    match matches.value_of("output") {
+        Some(output) => {
+            let mut file = File::create(output)
+                .chain_err(|| format!("Failed to create file '{}'", output))?;
+            file.write_all(source.as_bytes())
+                .chain_err(|| format!("Unable to Write file '{}'", output))
+        }
+        None => {
+            print!("{}", source);
+            Ok(())
         }
     }
 }
@@ -89,21 +606,94 @@ fn main() {
         .about("A simple vm with debug support")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
         .arg(Arg::from_usage("<input> 'Load the file in the vm'"))
         .arg(Arg::from_usage("-d --debug 'Start the vm in debug mode'"))
+        .arg(
+            Arg::from_usage("-x --command [FILE] 'Run the debug commands in FILE, in debug mode'")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::from_usage("--batch 'Exit after the -x commands, with the status of the program'")
+                .requires("command"),
+        )
+        .arg(
+            Arg::from_usage("--error-format [FORMAT] 'How errors are reported'")
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .global(true),
+        )
+        .arg(
+            Arg::from_usage("--encoding [ENCODING] 'Character encoding of the source file'")
+                .possible_values(&["auto", "utf-8", "latin-1"])
+                .default_value("auto")
+                .global(true),
+        )
+        .arg(
+            Arg::from_usage("--max-steps [N] 'Stop after executing N instructions'")
+                .validator(is_number),
+        )
+        .arg(
+            Arg::from_usage("--timeout [SECS] 'Stop after running for SECS seconds'")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(t) if t >= 0.0 && t.is_finite() => Ok(()),
+                    _ => Err(format!("'{}' is not a valid number of seconds", s)),
+                }),
+        )
+        .arg(Arg::from_usage("--stats 'Print execution statistics at the end of the run'"))
+        .arg(Arg::from_usage("--costs [FILE] 'Read the cost of each instruction from FILE'"))
+        .arg(Arg::from_usage("--print-cost 'Print the total cost at the end of the run'"))
+        .subcommand(
+            SubCommand::with_name("assemble")
+                .about("Write a program as a .vmb binary file")
+                .arg(Arg::from_usage("<input> 'The program to assemble'"))
+                .arg(Arg::from_usage(
+                    "-o --output [FILE] 'Where to write the binary. Defaults to <input>.vmb'",
+                ))
+                .arg(Arg::from_usage("-s --strip 'Leave out the source map'")),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Print a .vmb binary file as .vm source")
+                .arg(Arg::from_usage("<input> 'The program to print'")),
+        )
+        .subcommand(
+            SubCommand::with_name("optimize")
+                .about("Print a program after peephole optimizations")
+                .arg(Arg::from_usage("<input> 'The program to optimize'"))
+                .arg(Arg::from_usage(
+                    "-o --output [FILE] 'Where to write the program. Defaults to stdout'",
+                ))
+                .arg(Arg::from_usage("--explain 'Print each change made to stderr'")),
+        )
+        .subcommand(
+            SubCommand::with_name("to-c")
+                .about("Translate a program to a self-contained C program")
+                .arg(Arg::from_usage("<input> 'The program to translate'"))
+                .arg(Arg::from_usage(
+                    "-o --output [FILE] 'Where to write the C source. Defaults to stdout'",
+                )),
+        )
         .get_matches();
 
-    let mode = if matches.is_present("debug") {
-        Mode::Debug
-    } else {
-        Mode::Running
+    let (res, format) = match matches.subcommand() {
+        ("assemble", Some(m)) => (assemble(m).map_err(errors::Failure::from), m),
+        ("disasm", Some(m)) => (disasm(m).map_err(errors::Failure::from), m),
+        ("optimize", Some(m)) => (optimize(m).map_err(errors::Failure::from), m),
+        ("to-c", Some(m)) => (to_c(m).map_err(errors::Failure::from), m),
+        _ => (run(&matches), &matches),
     };
 
-    // There is a file argument
-    if let Some(file) = matches.value_of("input") {
-        // There are errors running the vm
-        if let Err(ref e) = vm::start(&file, mode) {
-            errors::print_errors(e);
-        }
+    let format = match format.value_of("error-format") {
+        Some("json") => errors::Format::Json,
+        _ => errors::Format::Human,
+    };
+
+    // There are errors running the vm
+    if let Err(ref f) = res {
+        errors::report(f, format);
+        process::exit(errors::exit_code(&f.error));
     }
 }
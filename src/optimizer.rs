@@ -0,0 +1,523 @@
+//! Peephole optimizer for `vm` programs
+//!
+//! The rewrites keep the behaviour of the program as run by the `vm`,
+//! and never move code across labels, since any label may be the target
+//! of a jump or a call.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use instructions::Instruction;
+use program::{Program, Span};
+use vm::Operand;
+
+/// A line of the program being optimized
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    Label(String),
+    Instr(Instruction, Span),
+}
+
+/// A change made by the optimizer
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    /// Source line of the first instruction replaced
+    pub line: usize,
+    /// Name of the optimization
+    pub rule: &'static str,
+    pub before: Vec<Instruction>,
+    pub after: Vec<Instruction>,
+}
+
+fn join(code: &[Instruction]) -> String {
+    let code: Vec<String> = code.iter().map(|i| i.to_string()).collect();
+    code.join("; ")
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}: {} => ", self.line, self.rule, join(&self.before))?;
+        if self.after.is_empty() {
+            write!(f, "(removed)")
+        } else {
+            write!(f, "{}", join(&self.after))
+        }
+    }
+}
+
+/// Optimize a program, returning the new program and the list of rewrites
+pub fn optimize(program: &Program) -> (Program, Vec<Rewrite>) {
+    let mut lines = to_lines(program);
+    let mut rewrites = Vec::new();
+
+    // Each rewrite makes the program shorter or a jump more direct,
+    // so running the passes until nothing changes always ends
+    loop {
+        let count = rewrites.len();
+        lines = fold_constants(lines, &mut rewrites);
+        lines = global_access(lines, &mut rewrites);
+        lines = thread_jumps(lines, &mut rewrites);
+        lines = remove_dead_code(lines, &mut rewrites);
+        if rewrites.len() == count {
+            break;
+        }
+    }
+
+    (from_lines(lines), rewrites)
+}
+
+fn to_lines(program: &Program) -> Vec<Line> {
+    let mut labels: Vec<(&usize, &String)> = program.labels.iter().map(|(k, v)| (v, k)).collect();
+    labels.sort();
+    let mut labels = labels.into_iter().peekable();
+
+    let mut lines = Vec::new();
+    for (i, instr) in program.code.iter().enumerate() {
+        while let Some((_, name)) = labels.next_if(|&(&addr, _)| addr <= i) {
+            lines.push(Line::Label(name.clone()));
+        }
+        let span = program.spans.get(i).cloned().unwrap_or_default();
+        lines.push(Line::Instr(instr.clone(), span));
+    }
+    for (_, name) in labels {
+        lines.push(Line::Label(name.clone()));
+    }
+    lines
+}
+
+fn from_lines(lines: Vec<Line>) -> Program {
+    let mut program = Program::default();
+    for line in lines {
+        match line {
+            Line::Label(name) => {
+                program.labels.insert(name, program.code.len());
+            }
+            Line::Instr(instr, span) => {
+                program.code.push(instr);
+                program.spans.push(span);
+            }
+        }
+    }
+    program
+}
+
+fn instrs(lines: &[Line]) -> Vec<Instruction> {
+    lines
+        .iter()
+        .filter_map(|l| match *l {
+            Line::Instr(ref i, _) => Some(i.clone()),
+            Line::Label(..) => None,
+        })
+        .collect()
+}
+
+fn line_of(lines: &[Line]) -> (usize, Span) {
+    lines
+        .iter()
+        .filter_map(|l| match *l {
+            Line::Instr(_, span) => Some((span.line, span)),
+            Line::Label(..) => None,
+        })
+        .next()
+        .unwrap_or_default()
+}
+
+/// The result of an integer operation, computed as the `vm` would
+fn fold(op: &Instruction, m: i32, n: i32) -> Option<i32> {
+    let f: fn(Operand, Operand) -> ::errors::Result<Operand> = match *op {
+        Instruction::Add => Operand::add,
+        Instruction::Sub => Operand::sub,
+        Instruction::Mul => Operand::mul,
+        Instruction::Div => Operand::div,
+        Instruction::Mod => Operand::module,
+        Instruction::Equal => Operand::equal,
+        Instruction::Inf => Operand::inf,
+        Instruction::Infeq => Operand::infeq,
+        Instruction::Sup => Operand::sup,
+        Instruction::Supeq => Operand::supeq,
+        _ => return None,
+    };
+
+    // Leave the operations that fail or overflow to run time
+    m.checked_add(n)?;
+    m.checked_sub(n)?;
+    n.checked_sub(m)?;
+    m.checked_mul(n)?;
+    if n != 0 {
+        m.checked_div(n)?;
+        m.checked_rem(n)?;
+    } else if matches!(*op, Instruction::Div | Instruction::Mod) {
+        // `mod` has no check of its own for a zero divisor
+        return None;
+    }
+
+    match f(Operand::Integer(n), Operand::Integer(m)) {
+        Ok(Operand::Integer(v)) => Some(v),
+        _ => None,
+    }
+}
+
+/// `pushi m; pushi n; <op>` becomes `pushi <m op n>`
+fn fold_constants(lines: Vec<Line>, rewrites: &mut Vec<Rewrite>) -> Vec<Line> {
+    let mut out: Vec<Line> = Vec::with_capacity(lines.len());
+    for line in lines {
+        let len = out.len();
+        let folded = match line {
+            Line::Instr(ref op, _) if len >= 2 => match (&out[len - 2], &out[len - 1]) {
+                (&Line::Instr(Instruction::Pushi(m), span), &Line::Instr(Instruction::Pushi(n), _)) => {
+                    fold(op, m, n).map(|v| (v, span))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+
+        match folded {
+            Some((v, span)) => {
+                let start = out.len() - 2;
+                let mut before = instrs(&out[start..]);
+                before.extend(instrs(&[line]));
+                out.truncate(start);
+
+                let after = Instruction::Pushi(v);
+                rewrites.push(Rewrite {
+                    line: span.line,
+                    rule: "constant folding",
+                    before,
+                    after: vec![after.clone()],
+                });
+                out.push(Line::Instr(after, span));
+            }
+            None => out.push(line),
+        }
+    }
+    out
+}
+
+/// Number of values an instruction pops and pushes, for the instructions
+/// that always continue with the next one
+fn stack_effect(instr: &Instruction) -> Option<(usize, usize)> {
+    Some(match *instr {
+        Instruction::Pushi(..)
+        | Instruction::Pushg(..)
+        | Instruction::Pushs(..)
+        | Instruction::Pusha(..)
+        | Instruction::Pushgp
+        | Instruction::Read => (0, 1),
+        Instruction::Pushn(n) if n >= 0 => (0, n as usize),
+        Instruction::Nop => (0, 0),
+        Instruction::Atoi => (1, 1),
+        Instruction::Writei | Instruction::Writes | Instruction::Storeg(..) => (1, 0),
+        Instruction::Loadn
+        | Instruction::Padd
+        | Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Mod
+        | Instruction::Equal
+        | Instruction::Inf
+        | Instruction::Infeq
+        | Instruction::Sup
+        | Instruction::Supeq => (2, 1),
+        Instruction::Storen => (3, 0),
+        _ => return None,
+    })
+}
+
+/// Index of the `storen` that writes the value computed by the
+/// instructions starting at `start`
+fn matching_storen(lines: &[Line], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        let instr = match *line {
+            Line::Instr(ref instr, _) => instr,
+            Line::Label(..) => return None,
+        };
+        if let Instruction::Storen = *instr {
+            if depth == 1 {
+                return Some(i);
+            }
+        }
+        let (pops, pushes) = stack_effect(instr)?;
+        if pops > depth {
+            return None;
+        }
+        depth = depth - pops + pushes;
+    }
+    None
+}
+
+/// `pushgp; pushi k; padd; pushi 0; loadn` becomes `pushg k`, and
+/// `pushgp; pushi k; padd; pushi 0; <value>; storen` becomes `<value>; storeg k`
+fn global_access(lines: Vec<Line>, rewrites: &mut Vec<Rewrite>) -> Vec<Line> {
+    let mut out = Vec::with_capacity(lines.len());
+    let mut i = 0;
+    while i < lines.len() {
+        let global = match lines[i..] {
+            [Line::Instr(Instruction::Pushgp, _), Line::Instr(Instruction::Pushi(k), _), Line::Instr(Instruction::Padd, _), Line::Instr(Instruction::Pushi(0), _), ..]
+                if k >= 0 =>
+            {
+                Some(k as usize)
+            }
+            _ => None,
+        };
+
+        if let Some(k) = global {
+            let (line, span) = line_of(&lines[i..]);
+
+            if let Some(&Line::Instr(Instruction::Loadn, _)) = lines.get(i + 4) {
+                let after = Instruction::Pushg(k);
+                rewrites.push(Rewrite {
+                    line,
+                    rule: "global variable read",
+                    before: instrs(&lines[i..i + 5]),
+                    after: vec![after.clone()],
+                });
+                out.push(Line::Instr(after, span));
+                i += 5;
+                continue;
+            }
+
+            if let Some(end) = matching_storen(&lines, i + 4) {
+                let value = &lines[i + 4..end];
+                let after = Instruction::Storeg(k);
+
+                let mut replaced = instrs(value);
+                replaced.push(after.clone());
+                rewrites.push(Rewrite {
+                    line,
+                    rule: "global variable write",
+                    before: instrs(&lines[i..=end]),
+                    after: replaced,
+                });
+
+                out.extend_from_slice(value);
+                out.push(Line::Instr(after, line_of(&lines[end..]).1));
+                i = end + 1;
+                continue;
+            }
+        }
+
+        out.push(lines[i].clone());
+        i += 1;
+    }
+    out
+}
+
+/// The instruction run after jumping to each label
+fn label_targets(lines: &[Line]) -> HashMap<String, Option<Instruction>> {
+    let mut targets = HashMap::new();
+    let mut pending = Vec::new();
+    for line in lines {
+        match *line {
+            Line::Label(ref name) => pending.push(name.clone()),
+            Line::Instr(ref instr, _) => {
+                for name in pending.drain(..) {
+                    targets.insert(name, Some(instr.clone()));
+                }
+            }
+        }
+    }
+    for name in pending {
+        targets.insert(name, None);
+    }
+    targets
+}
+
+/// Follow a chain of jumps, stopping on loops
+fn final_target(targets: &HashMap<String, Option<Instruction>>, label: &str) -> String {
+    let mut current = label.to_string();
+    let mut seen = vec![current.clone()];
+    while let Some(&Some(Instruction::Jump(ref next))) = targets.get(&current) {
+        if seen.contains(next) {
+            return label.to_string();
+        }
+        seen.push(next.clone());
+        current = next.clone();
+    }
+    current
+}
+
+/// `jump` and `jz` to a `jump` go directly to its target, and
+/// a `jump` to the next instruction is removed
+fn thread_jumps(lines: Vec<Line>, rewrites: &mut Vec<Rewrite>) -> Vec<Line> {
+    let targets = label_targets(&lines);
+
+    let mut out = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        let (instr, span) = match *line {
+            Line::Instr(ref instr, span) => (instr, span),
+            Line::Label(..) => {
+                out.push(line.clone());
+                continue;
+            }
+        };
+
+        let label = match *instr {
+            Instruction::Jump(ref label) | Instruction::Jz(ref label) => label,
+            _ => {
+                out.push(line.clone());
+                continue;
+            }
+        };
+
+        // Only labels are between the jump and its target
+        let to_next = lines[i + 1..]
+            .iter()
+            .take_while(|l| matches!(**l, Line::Label(..)))
+            .any(|l| *l == Line::Label(label.clone()));
+        if to_next {
+            if let Instruction::Jump(..) = *instr {
+                rewrites.push(Rewrite {
+                    line: span.line,
+                    rule: "jump to next instruction",
+                    before: vec![instr.clone()],
+                    after: vec![],
+                });
+                continue;
+            }
+        }
+
+        let target = final_target(&targets, label);
+        if target == *label {
+            out.push(line.clone());
+            continue;
+        }
+
+        let after = match *instr {
+            Instruction::Jump(..) => Instruction::Jump(target),
+            _ => Instruction::Jz(target),
+        };
+        rewrites.push(Rewrite {
+            line: span.line,
+            rule: "jump threading",
+            before: vec![instr.clone()],
+            after: vec![after.clone()],
+        });
+        out.push(Line::Instr(after, span));
+    }
+    out
+}
+
+/// Instructions after a `jump` or `stop` are never run until the next label
+fn remove_dead_code(lines: Vec<Line>, rewrites: &mut Vec<Rewrite>) -> Vec<Line> {
+    let mut out = Vec::with_capacity(lines.len());
+    let mut dead: Vec<Line> = Vec::new();
+    let mut reachable = true;
+
+    for line in lines {
+        match line {
+            Line::Label(..) => {
+                if !dead.is_empty() {
+                    rewrites.push(Rewrite {
+                        line: line_of(&dead).0,
+                        rule: "dead code",
+                        before: instrs(&dead),
+                        after: vec![],
+                    });
+                    dead.clear();
+                }
+                reachable = true;
+                out.push(line);
+            }
+            Line::Instr(..) if !reachable => dead.push(line),
+            Line::Instr(ref instr, _) => {
+                if let Instruction::Jump(..) | Instruction::Stop = *instr {
+                    reachable = false;
+                }
+                out.push(line.clone());
+            }
+        }
+    }
+    if !dead.is_empty() {
+        rewrites.push(Rewrite {
+            line: line_of(&dead).0,
+            rule: "dead code",
+            before: instrs(&dead),
+            after: vec![],
+        });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use instructions::Instruction as ins;
+    use parser::parse;
+
+    fn code(input: &str) -> Vec<ins> {
+        optimize(&parse(input).unwrap()).0.code
+    }
+
+    #[test]
+    fn constant_folding() {
+        assert_eq!(
+            code("pushi 2\npushi 3\nadd\npushi 4\nmul"),
+            [ins::Pushi(20)]
+        );
+    }
+
+    #[test]
+    fn constant_folding_keeps_vm_order() {
+        // `sub` pops `n` then `m` and stacks `n - m`
+        assert_eq!(code("pushi 5\npushi 3\nsub"), [ins::Pushi(-2)]);
+    }
+
+    #[test]
+    fn constant_folding_keeps_errors() {
+        assert_eq!(
+            code("pushi 1\npushi 0\ndiv"),
+            [ins::Pushi(1), ins::Pushi(0), ins::Div]
+        );
+        assert_eq!(
+            code("pushi 1\npushi 0\nmod"),
+            [ins::Pushi(1), ins::Pushi(0), ins::Mod]
+        );
+    }
+
+    #[test]
+    fn constant_folding_stops_at_labels() {
+        assert_eq!(
+            code("pushi 1\nl:\npushi 2\nadd\njump l"),
+            [ins::Pushi(1), ins::Pushi(2), ins::Add, ins::Jump("l".to_string())]
+        );
+    }
+
+    #[test]
+    fn global_access() {
+        assert_eq!(
+            code("pushgp\npushi 1\npadd\npushi 0\nloadn"),
+            [ins::Pushg(1)]
+        );
+        assert_eq!(
+            code("pushgp\npushi 2\npadd\npushi 0\npushg 0\npushi 1\nadd\nstoren"),
+            [ins::Pushg(0), ins::Pushi(1), ins::Add, ins::Storeg(2)]
+        );
+    }
+
+    #[test]
+    fn jump_threading() {
+        assert_eq!(
+            code("jz a\nstop\na:\njump b\nb:\nstop"),
+            [ins::Jz("b".to_string()), ins::Stop, ins::Stop]
+        );
+    }
+
+    #[test]
+    fn jump_loop() {
+        assert_eq!(
+            code("a:\njump b\nstop\nb:\njump a"),
+            [ins::Jump("a".to_string())]
+        );
+    }
+
+    #[test]
+    fn dead_code() {
+        assert_eq!(
+            code("start\nstop\npushi 1\nwritei\nl:\nstop"),
+            [ins::Start, ins::Stop, ins::Stop]
+        );
+    }
+}
@@ -1,290 +1,730 @@
-pub use self::parser_impl::parse;
+//! Parser for `vm` source files
+//!
+//! A single left to right pass over the input, so the time taken grows
+//! linearly with the size of the program and the call depth stays constant.
 
-mod parser_impl {
+use std::collections::HashMap;
+use std::fmt;
 
-    use pest::prelude::*;
-    use std::collections::HashMap;
-    use errors::*;
+use ansi_term::Color::Red;
 
-    use instructions::Instruction;
-    use instructions::Instruction as ins;
+use errors::*;
+use instructions::Instruction;
+use instructions::Instruction as ins;
+use program::{Program, Span};
+
+/// A parsed line of source, either a label or an instruction
+enum Line {
+    Instr(Instruction),
+    Label(String),
+}
+
+/// A problem found in the source, reported with the line it is on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// Where the offending text is
+    pub span: Span,
+    /// What is wrong with it
+    pub message: String,
+    /// What would have been accepted in its place
+    pub expected: Option<String>,
+    /// A likely fix, such as the instruction closest to a misspelled one
+    pub help: Option<String>,
+    /// The source line the problem starts on
+    pub source: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = &self.span;
+        let gutter = " ".repeat(span.line.to_string().len());
+        writeln!(f, "{} {} => {}", Red.paint("Syntax Error:"), span, self.message)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", span.line, self.source)?;
+
+        // keep the tabs so the carets line up with the source above
+        let indent: String = self.source
+            .chars()
+            .take(span.col - 1)
+            .map(|c| if c == '\t' { c } else { ' ' })
+            .collect();
+        let end_col = if span.end_line == span.line {
+            span.end_col
+        } else {
+            self.source.chars().count() + 1
+        };
+        let carets = "^".repeat(end_col.saturating_sub(span.col).max(1));
+        write!(f, "{} | {}{}", gutter, indent, Red.paint(carets))?;
+        if let Some(ref help) = self.help {
+            write!(f, " {}", help)?;
+        }
+        if let Some(ref expected) = self.expected {
+            write!(f, "\n{} = expected {}", gutter, expected)?;
+        }
+        Ok(())
+    }
+}
+
+/// Instructions without operands, in the order they are tried
+///
+/// A keyword that is a prefix of another must come after it.
+const ATOMS: &[&str] = &[
+    "padd", "add", "sub", "mul", "div", "mod", "not", "infeq", "inf", "supeq",
+    "sup", "fadd", "fsub", "fmul", "fdiv", "fcos", "fsin",
+    "finfeq", "finf", "fsupeq", "fsup", "concat", "equal", "atoi", "atof",
+    "itof", "ftoi", "stri", "strf",
+    "pushsp", "pushfp", "pushgp", "loadn", "storen", "swap",
+    "writei", "writef", "writes", "read", "call", "return",
+    "drawpoint", "drawline", "drawcircle",
+    "cleardrawingarea", "opendrawingarea", "setcolor", "refresh",
+    "start", "nop", "stop", "allocn", "free", "dupn", "popn",
+];
+
+/// Instructions with an integer operand
+const INTS: &[&str] = &[
+    "pushi", "pushn", "pushg", "pushl", "load",
+    "dup", "pop", "storel", "storeg", "alloc",
+];
+
+/// Instructions with a label operand
+const JUMPS: &[&str] = &["jump", "jz", "pusha"];
+
+/// Instructions with a string operand
+const STRINGS: &[&str] = &["pushs", "err"];
+
+fn atom(name: &str) -> Result<Instruction> {
+    Ok(match name {
+        "padd" => ins::Padd,
+        "add" => ins::Add,
+        "sub" => ins::Sub,
+        "mul" => ins::Mul,
+        "div" => ins::Div,
+        "mod" => ins::Mod,
+        "inf" => ins::Inf,
+        "infeq" => ins::Infeq,
+        "sup" => ins::Sup,
+        "supeq" => ins::Supeq,
+        "equal" => ins::Equal,
+        "atoi" => ins::Atoi,
+        "pushgp" => ins::Pushgp,
+        "loadn" => ins::Loadn,
+        "storen" => ins::Storen,
+        "writei" => ins::Writei,
+        "writes" => ins::Writes,
+        "read" => ins::Read,
+        "call" => ins::Call,
+        "return" => ins::Return,
+        "start" => ins::Start,
+        "nop" => ins::Nop,
+        "stop" => ins::Stop,
+        _ => bail!("`{}` is not implemented", name),
+    })
+}
+
+fn int(name: &str, value: &str) -> Result<Instruction> {
+    Ok(match name {
+        "pushg" => ins::Pushg(value.parse().chain_err(|| positive(value))?),
+        "storeg" => ins::Storeg(value.parse().chain_err(|| positive(value))?),
+        "pushi" => ins::Pushi(value.parse().chain_err(|| integer(value))?),
+        "pushn" => ins::Pushn(value.parse().chain_err(|| integer(value))?),
+        _ => bail!("`{}` is not implemented", name),
+    })
+}
 
-    use self::LInstruction::*;
+/// The instructions that can be run, in alphabetical order
+fn implemented() -> Vec<&'static str> {
+    let mut names: Vec<&'static str> = ATOMS
+        .iter()
+        .filter(|k| atom(k).is_ok())
+        .chain(INTS.iter().filter(|k| int(k, "0").is_ok()))
+        .chain(STRINGS)
+        .chain(JUMPS)
+        .cloned()
+        .collect();
+    names.sort();
+    names
+}
 
-    pub enum LInstruction {
-        Instr(Instruction),
-        Label(String),
+/// What each instruction with an operand expects after it
+fn operand_of(keyword: &str) -> Option<&'static str> {
+    if INTS.contains(&keyword) {
+        Some("an integer")
+    } else if STRINGS.contains(&keyword) {
+        Some("a string in double quotes")
+    } else if JUMPS.contains(&keyword) {
+        Some("a label")
+    } else if keyword == "pushf" {
+        Some("a number")
+    } else if keyword == "check" {
+        Some("two integers separated by a comma")
+    } else {
+        None
     }
+}
 
-    impl From<LInstruction> for Option<Instruction> {
-        fn from(inst: LInstruction) -> Self {
-            match inst {
-                Label(..) => None,
-                Instr(i) => Some(i),
+/// Number of single character edits needed to turn `a` into `b`
+///
+/// Swapping two adjacent characters counts as a single edit, as it is a
+/// common typo.
+fn distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // d[i][j] is the distance between the first i characters of a and the first j of b
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    d[0] = (0..b.len() + 1).collect();
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for i in 1..a.len() + 1 {
+        for j in 1..b.len() + 1 {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            d[i][j] = (d[i - 1][j - 1] + cost)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
             }
         }
     }
+    d[a.len()][b.len()]
+}
 
-    impl_rdp! {
-        grammar! {
-            // Lexical Rules
-            digit = _{ ['0'..'9'] }
-            alpha = _{ ['A'..'Z'] | ['a'..'z'] }
-            ident = @{ ( alpha | ["_"] ) ~ ( alpha | digit | ["_"] | ["'"] )* }
+/// The instruction closest to `word`, if it is close enough to be a typo
+///
+/// There is no suggestion when several instructions are as close.
+fn closest(word: &str) -> Option<&'static str> {
+    let word = word.to_ascii_lowercase();
+    let limit = (word.len() / 3).max(1);
+    let mut candidates: Vec<(usize, &'static str)> = implemented()
+        .into_iter()
+        .map(|k| (distance(&word, k), k))
+        .filter(|&(d, _)| d <= limit)
+        .collect();
+    candidates.sort();
+    match candidates[..] {
+        [(d, _), (next, _), ..] if d == next => None,
+        [(_, k), ..] => Some(k),
+        [] => None,
+    }
+}
+
+fn positive(value: &str) -> String {
+    format!("`{}` is not a positive integer", value)
+}
+
+fn integer(value: &str) -> String {
+    format!("`{}` is not a valid integer", value)
+}
+
+/// Decode the escape sequences of a string literal
+pub fn unescape(raw: &str) -> Result<String> {
+    let mut res = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => res.push('"'),
+            Some('\\') => res.push('\\'),
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some('u') => res.push(unicode(&mut chars)?),
+            Some(c) => bail!("unknown escape `\\{}` in string", c),
+            None => bail!("string ends with a lone `\\`"),
+        }
+    }
+    Ok(res)
+}
+
+/// Decode the `{...}` part of a `\u{...}` escape, with 1 to 6 hex digits
+fn unicode(chars: &mut ::std::str::Chars) -> Result<char> {
+    if chars.next() != Some('{') {
+        bail!("expected `{{` after `\\u` in string");
+    }
+    let mut hex = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) => hex.push(c),
+            None => bail!("unterminated `\\u{{{}` in string", hex),
+        }
+    }
+    let code = if hex.is_empty() || hex.len() > 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        None
+    } else {
+        u32::from_str_radix(&hex, 16).ok()
+    };
+    match code.and_then(::std::char::from_u32) {
+        Some(c) => Ok(c),
+        None => bail!("`\\u{{{}}}` is not a unicode character", hex),
+    }
+}
+
+fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
 
-            integer = @{ ["-"]? ~ digit+}
-            float = @{
-                ["-"]? ~ digit+ ~ (["."] ~ digit*)? ~ ((["e"]|["E"]) ~ (["+"]|["-"])? ~ digit+)?
+fn is_ident(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'\''
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    bytes: &'a [u8],
+    pos: usize,
+    /// Problems found so far, with the byte offsets of each
+    problems: Vec<(Diagnostic, (usize, usize))>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Parser {
+            input,
+            bytes: input.as_bytes(),
+            pos: 0,
+            problems: Vec::new(),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).cloned()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    /// Consume `c` if it is next
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consume the first of `keywords` that is next, ignoring case
+    fn keyword(&mut self, keywords: &[&'static str]) -> Option<&'static str> {
+        let rest = &self.bytes[self.pos..];
+        let found = keywords.iter().find(|k| {
+            rest.len() >= k.len() && rest[..k.len()].eq_ignore_ascii_case(k.as_bytes())
+        });
+        if let Some(k) = found {
+            self.pos += k.len();
+        }
+        found.cloned()
+    }
+
+    /// Consume spaces and tabs, returning how many there were
+    fn spaces(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b' ') | Some(b'\t') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    /// Consume a `//` comment up to the end of the line
+    fn comment(&mut self) -> bool {
+        if !self.bytes[self.pos..].starts_with(b"//") {
+            return false;
+        }
+        while let Some(c) = self.peek() {
+            if c == b'\n' || c == b'\r' {
+                break;
             }
+            self.pos += 1;
+        }
+        true
+    }
 
-            string = @{ ["\""] ~ inner_string ~ ["\""] }
-            inner_string = { (["\\\""]|!["\""] ~ any)* }
-
-            padd = {[i"padd"]}
-            add = {[i"add"]}
-            sub = {[i"sub"]}
-            mul = {[i"mul"]}
-            div = {[i"div"]}
-            mod_ = {[i"mod"]}
-            not = {[i"not"]}
-            inf = {[i"inf"]}
-            infeq = {[i"infeq"]}
-            sup = {[i"sup"]}
-
-            supeq = {[i"supeq"]}
-            fadd = {[i"fadd"]}
-            fsub = {[i"fsub"]}
-            fmul = {[i"fmul"]}
-            fdiv = {[i"fdiv"]}
-            fcos = {[i"fcos"]}
-            fsin = {[i"fsin"]}
-
-            finf = {[i"finf"]}
-            finfeq = {[i"finfeq"]}
-            fsup = {[i"fsup"]}
-            fsupeq = {[i"fsupeq"]}
-            concat = {[i"concat"]}
-            equal = {[i"equal"]}
-            atoi = {[i"atoi"]}
-            atof = {[i"atof"]}
-
-            itof = {[i"itof"]}
-            ftoi = {[i"ftoi"]}
-            stri = {[i"stri"]}
-            strf = {[i"strf"]}
-
-            pushsp = {[i"pushsp"]}
-            pushfp = {[i"pushfp"]}
-            pushgp = {[i"pushgp"]}
-            loadn = {[i"loadn"]}
-            storen = {[i"storen"]}
-            swap = {[i"swap"]}
-
-            writei = {[i"writei"]}
-            writef = {[i"writef"]}
-            writes = {[i"writes"]}
-            read = {[i"read"]}
-            call = {[i"call"]}
-            return_ = {[i"return"]}
-
-            drawpoint = {[i"drawpoint"]}
-            drawline = {[i"drawline"]}
-            drawcircle = {[i"drawcircle"]}
-
-            cleardrawingarea = {[i"cleardrawingarea"]}
-            opendrawingarea = {[i"opendrawingarea"]}
-            setcolor = {[i"setcolor"]}
-            refresh = {[i"refresh"]}
-
-            start = {[i"start"]}
-            nop = {[i"nop"]}
-            stop = {[i"stop"]}
-            allocn = {[i"allocn"]}
-            free = {[i"free"]}
-            dupn = {[i"dupn"]}
-            popn = {[i"popn"]}
-
-
-            pushi = {[i"pushi"]}
-            pushn = {[i"pushn"]}
-            pushg = {[i"pushg"]}
-            pushl = {[i"pushl"]}
-            load = {[i"load"]}
-
-            dup = {[i"dup"]}
-            pop = {[i"pop"]}
-            storel = {[i"storel"]}
-            storeg = {[i"storeg"]}
-            alloc = {[i"alloc"]}
-
-            pushf = {[i"pushf"]}
-
-            pushs = {[i"pushs"]}
-            err = {[i"err"]}
-
-            check = {[i"check"]}
-
-            jump = {[i"jump"]}
-            jz = {[i"jz"]}
-            pusha = {[i"pusha"]}
-
-            sp = _{ [" "] | ["\t"] }
-            nl = _{ ["\n"] | ["\r"] }
-            ws = _{ sp | nl }
-
-            // Grammar Rules
-            code = _{ soi ~ ws* ~ instr? ~ (sp* ~ nl ~ ws* ~ instr)* ~ ws* ~ eoi }
-
-            instr = @{
-                ident ~ sp* ~ [":"]
-                | instr_atom
-                | instr_int ~ sp+ ~ integer
-                | pushf ~ sp+ ~ float
-                | ( pushs | err) ~ sp+ ~ string
-                | check ~ sp+ ~ integer ~ sp* ~ [","] ~ sp* ~ integer
-                | (jump | jz | pusha) ~ sp+ ~ ident
+    /// Consume whitespace, newlines and comments
+    fn blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') => self.pos += 1,
+                _ => if !self.comment() {
+                    break;
+                },
             }
-            instr_atom = {
-                padd | add | sub | mul | div | mod_ | not | infeq | inf | supeq
-                | sup | fadd | fsub | fmul | fdiv | fcos | fsin
-                | finfeq | finf | fsupeq | fsup | concat | equal | atoi | atof
-                | itof | ftoi | stri | strf
-                | pushsp | pushfp | pushgp | loadn | storen | swap
-                | writei | writef | writes | read | call | return_
-                | drawpoint | drawline | drawcircle
-                | cleardrawingarea | opendrawingarea | setcolor | refresh
-                | start | nop | stop | allocn | free | dupn | popn
+        }
+    }
+
+    /// Whether only a comment is left before the next line
+    fn at_line_end(&self) -> bool {
+        match self.peek() {
+            None | Some(b'\n') | Some(b'\r') => true,
+            _ => self.bytes[self.pos..].starts_with(b"//"),
+        }
+    }
+
+    /// Consume the end of a line, with any whitespace and comments around it
+    fn separator(&mut self) -> bool {
+        while self.spaces() > 0 || self.comment() {}
+        match self.peek() {
+            Some(b'\n') | Some(b'\r') => {
+                self.blank();
+                true
             }
-            instr_int = {
-                pushi | pushn | pushg | pushl | load
-                | dup | pop | storel | storeg | alloc
+            _ => false,
+        }
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        if !self.peek().is_some_and(is_ident_start) {
+            return None;
+        }
+        while self.peek().is_some_and(is_ident) {
+            self.pos += 1;
+        }
+        Some(&self.input[start..self.pos])
+    }
+
+    fn digits(&mut self) -> bool {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    fn integer(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        self.eat(b'-');
+        if self.digits() {
+            Some(&self.input[start..self.pos])
+        } else {
+            None
+        }
+    }
+
+    fn float(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        self.integer()?;
+        if self.eat(b'.') {
+            self.digits();
+        }
+        let exponent = self.pos;
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
             }
-            comment = _{ ["//"] ~ (!nl ~ any)* }
-        }
-
-        process! {
-            compute(&self) -> Result<Vec<LInstruction>> {
-                (a: instr, head: instruction(), tail: compute()) => {
-                    let mut t = tail?;
-                    let h = head.chain_err(|| {
-                        let i = self.input();
-                        let (line, col) = i.line_col(a.start);
-                        format!(
-                            "Instruction '{}' at line({}), col({})",
-                            i.slice(a.start, a.end),
-                            line,
-                            col
-                        )
-                    })?;
-                    t.insert(0, h);
-                    Ok(t)
-                },
-                () => {
-                    Ok(Vec::new())
-                }
+            if !self.digits() {
+                self.pos = exponent;
             }
+        }
+        Some(&self.input[start..self.pos])
+    }
 
-            instruction(&self) -> Result<LInstruction> {
-                (&id: ident) => Ok(Label(id.to_string())),
-
-                (_: pushs, _: string, &s: inner_string) => Ok(Instr(ins::Pushs(s.to_string()))),
-                (_: err, _: string, &s: inner_string) => Ok(Instr(ins::Err(s.to_string()))),
+    /// A double quoted string, returning the text between the quotes as written
+    fn string(&mut self) -> Option<&'a str> {
+        if !self.eat(b'"') {
+            return None;
+        }
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                None => return None,
+                Some(b'"') => break,
+                // the escaped character is decoded later, it only must not end the string
+                Some(b'\\') if self.pos + 1 < self.bytes.len() => self.pos += 2,
+                Some(_) => self.pos += 1,
+            }
+        }
+        let s = &self.input[start..self.pos];
+        self.pos += 1;
+        Some(s)
+    }
 
-                (_: jump, &id: ident) => Ok(Instr(ins::Jump(id.to_string()))),
-                (_: jz, &id: ident) => Ok(Instr(ins::Jz(id.to_string()))),
-                (_: pusha, &id: ident) => Ok(Instr(ins::Pusha(id.to_string()))),
+    /// Run `f`, rewinding to where it started if it does not match
+    fn attempt<T, F>(&mut self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Self) -> Option<T>,
+    {
+        let start = self.pos;
+        let res = f(self);
+        if res.is_none() {
+            self.pos = start;
+        }
+        res
+    }
 
-                (_: instr_atom, res: atom()) => res,
-                (_: instr_int, res: int()) => res,
-                () => Err("Failed to parse Instruction".into())
+    /// One of `keywords` followed by at least one space and an operand
+    fn operand<T, F>(&mut self, keywords: &[&'static str], f: F) -> Option<(&'static str, T)>
+    where
+        F: FnOnce(&mut Self) -> Option<T>,
+    {
+        self.attempt(|p| {
+            let k = p.keyword(keywords)?;
+            if p.spaces() == 0 {
+                return None;
             }
+            f(p).map(|v| (k, v))
+        })
+    }
 
-            int(&self) -> Result<LInstruction> {
-                (_: pushg, &i: integer) => Ok(Instr(ins::Pushg(
-                        i.parse().chain_err(|| "value is not a positive integer")?
-                    ))),
-                (_: storeg, &i: integer) => Ok(Instr(ins::Storeg(
-                        i.parse().chain_err(|| "value is not a positive integer")?
-                    ))),
-                (_: pushi, &i: integer) => Ok(Instr(ins::Pushi(
-                        i.parse().chain_err(|| "value is not a integer")?
-                    ))),
-                (_: pushn, &i: integer) =>Ok(Instr(ins::Pushn(
-                        i.parse().chain_err(|| "value is not a integer")?
-                    ))),
-                () => Err("Not Implemented".into()),
+    fn label(&mut self) -> Option<&'a str> {
+        self.attempt(|p| {
+            let id = p.ident()?;
+            p.spaces();
+            if p.eat(b':') {
+                Some(id)
+            } else {
+                None
             }
+        })
+    }
 
-            atom(&self) -> Result<LInstruction> {
-                (_: padd) => Ok(Instr(ins::Padd)),
-                (_: add) => Ok(Instr(ins::Add)),
-                (_: sub) => Ok(Instr(ins::Sub)),
-                (_: mul) => Ok(Instr(ins::Mul)),
-                (_: div) => Ok(Instr(ins::Div)),
-                (_: mod_) => Ok(Instr(ins::Mod)),
-                (_: inf) => Ok(Instr(ins::Inf)),
-                (_: infeq) => Ok(Instr(ins::Infeq)),
-                (_: sup) => Ok(Instr(ins::Sup)),
-
-                (_: supeq) => Ok(Instr(ins::Supeq)),
-
-                (_: equal) => Ok(Instr(ins::Equal)),
-                (_: atoi) => Ok(Instr(ins::Atoi)),
-
-                (_: pushgp) => Ok(Instr(ins::Pushgp)),
-                (_: loadn) => Ok(Instr(ins::Loadn)),
-                (_: storen) => Ok(Instr(ins::Storen)),
-
-                (_: writei) => Ok(Instr(ins::Writei)),
-                (_: writes) => Ok(Instr(ins::Writes)),
-                (_: read) => Ok(Instr(ins::Read)),
-                (_: call) => Ok(Instr(ins::Call)),
-                (_: return_) => Ok(Instr(ins::Return)),
-
-                (_: start) => Ok(Instr(ins::Start)),
-                (_: nop) => Ok(Instr(ins::Nop)),
-                (_: stop) => Ok(Instr(ins::Stop)),
-                () => Err("Not Implemented".into()),
+    fn check(&mut self) -> Option<()> {
+        self.integer()?;
+        self.spaces();
+        if !self.eat(b',') {
+            return None;
+        }
+        self.spaces();
+        self.integer().map(|_| ())
+    }
+
+    /// Try each form of instruction in turn, the first that matches wins.
+    /// Invalid operands are returned as errors rather than rejecting the line.
+    fn instr(&mut self) -> Option<Result<Line>> {
+        if let Some(id) = self.label() {
+            return Some(Ok(Line::Label(id.to_string())));
+        }
+        if let Some(k) = self.keyword(ATOMS) {
+            return Some(atom(k).map(Line::Instr));
+        }
+        if let Some((k, i)) = self.operand(INTS, Self::integer) {
+            return Some(int(k, i).map(Line::Instr));
+        }
+        if self.operand(&["pushf"], Self::float).is_some() {
+            return Some(Err("`pushf` is not implemented".into()));
+        }
+        if let Some((k, s)) = self.operand(STRINGS, Self::string) {
+            return Some(unescape(s).map(|s| Line::Instr(if k == "pushs" { ins::Pushs(s) } else { ins::Err(s) })));
+        }
+        if self.operand(&["check"], Self::check).is_some() {
+            return Some(Err("`check` is not implemented".into()));
+        }
+        self.operand(JUMPS, Self::ident).map(|(k, id)| {
+            let id = id.to_string();
+            Ok(Line::Instr(match k {
+                "jump" => ins::Jump(id),
+                "jz" => ins::Jz(id),
+                _ => ins::Pusha(id),
+            }))
+        })
+    }
+
+    /// Consume everything up to the end of the line
+    fn skip_line(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == b'\n' || c == b'\r' {
+                break;
             }
+            self.pos += 1;
         }
     }
 
-    pub fn parse(input: &str) -> Result<(Vec<Instruction>, HashMap<String, usize>)> {
-        let mut parser = Rdp::new(StringInput::new(input));
+    /// The offsets of the text from the current position up to the next whitespace
+    fn token(&self) -> (usize, usize) {
+        let len = self.bytes[self.pos..]
+            .iter()
+            .take_while(|c| !b" \t\n\r".contains(c))
+            .count();
+        (self.pos, self.pos + len)
+    }
 
-        parser.code();
+    /// Describe the text between the offsets for a message
+    fn found(&self, start: usize, end: usize) -> String {
+        if start < end {
+            format!("unexpected `{}`", &self.input[start..end])
+        } else if end == self.bytes.len() {
+            "unexpected end of file".to_string()
+        } else {
+            "unexpected end of line".to_string()
+        }
+    }
+
+    fn report(&mut self, start: usize, end: usize, message: String, expected: Option<String>, help: Option<String>) {
+        let line_start = self.input[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.input[start..].find('\n').map_or(self.input.len(), |i| start + i);
+        let source = self.input[line_start..line_end]
+            .trim_end_matches('\r')
+            .replace('\r', " ");
+
+        let diagnostic = Diagnostic {
+            span: Span::default(),
+            message,
+            expected,
+            help,
+            source,
+        };
+        self.problems.push((diagnostic, (start, end)));
+    }
 
-        if !parser.end() {
-            let (r, pos) = parser.expected();
-            let (line, col) = parser.input().line_col(pos);
-            bail!("line({}), col({}) => expected rules: {:?}", line, col, r);
+    /// Report why no instruction starts at the current position
+    fn unknown(&mut self) {
+        let start = self.pos;
+        let word_len = match self.peek() {
+            Some(c) if is_ident_start(c) => self.bytes[start..].iter().take_while(|&&c| is_ident(c)).count(),
+            _ => 0,
+        };
+        let word = &self.input[start..start + word_len];
+        let keyword = word.to_ascii_lowercase();
+
+        // a known instruction with a missing or malformed operand
+        if let Some(operand) = operand_of(&keyword) {
+            self.pos += word_len;
+            let spaced = self.spaces() > 0;
+            let (s, e) = self.token();
+            let (message, expected) = if self.peek() == Some(b'"') && spaced {
+                self.skip_line();
+                ("unterminated string".to_string(), "a closing `\"`".to_string())
+            } else if !spaced && s < e {
+                (self.found(s, e), format!("a space between `{}` and its operand", word))
+            } else {
+                (self.found(s, e), format!("{} after `{}`", operand, word))
+            };
+            let end = e.max(self.pos);
+            return self.report(s, end, message, Some(expected), None);
         }
 
-        let labeled_instrs = parser.compute()?;
-        let mut labels = HashMap::new();
+        let (_, end) = self.token();
+        let text = &self.input[start..end];
+        let message = if word_len == 0 {
+            self.found(start, end)
+        } else {
+            format!("unknown instruction `{}`", text)
+        };
 
-        let mut acc = 0;
-        // insert labels with the correct pointer
-        for (i, instr) in labeled_instrs.iter().enumerate() {
-            if let Label(ref val) = *instr {
-                labels.insert(val.clone(), i - acc);
-                acc += 1;
+        // an operand written right after its instruction, as in `pushi2`
+        let joined = implemented()
+            .into_iter()
+            .filter(|k| operand_of(k).is_some() && keyword.len() > k.len())
+            .find(|k| keyword.starts_with(k));
+        let help = match joined {
+            Some(k) => Some(format!("did you mean `{} {}`?", &text[..k.len()], &text[k.len()..])),
+            None if word_len > 0 => closest(word).map(|k| format!("did you mean `{}`?", k)),
+            None => None,
+        };
+        let expected = format!("a label or one of the instructions: {}", implemented().join(", "));
+        self.report(start, end, message, Some(expected), help);
+    }
+
+    /// Report the text following the instruction that starts at `start`
+    fn trailing(&mut self, start: usize) {
+        // strings can span lines, only the first is worth repeating
+        let instr = self.input[start..self.pos]
+            .lines()
+            .next()
+            .unwrap_or("")
+            .trim_end()
+            .to_string();
+        let (s, e) = self.token();
+
+        let next = self.pos;
+        let help = if self.instr().is_some() {
+            Some("each instruction goes on its own line".to_string())
+        } else {
+            None
+        };
+        self.pos = next;
+
+        let message = self.found(s, e);
+        self.report(s, e, message, Some(format!("the end of the line after `{}`", instr)), help);
+    }
+
+    /// Split the input into lines, with the byte offsets of each
+    fn lines(mut self) -> Result<Vec<(Line, (usize, usize))>> {
+        let mut lines = Vec::new();
+
+        self.blank();
+        while !self.at_end() {
+            let start = self.pos;
+            match self.instr() {
+                Some(Ok(line)) => {
+                    let label = matches!(line, Line::Label(..));
+                    lines.push((line, (start, self.pos)));
+                    // a label can share its line with the instruction it names
+                    if label {
+                        self.spaces();
+                        if !self.at_line_end() {
+                            continue;
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    let causes: Vec<String> = e.iter().map(|e| e.to_string()).collect();
+                    let end = self.pos;
+                    self.report(start, end, causes.join(": "), None, None);
+                }
+                None => {
+                    self.unknown();
+                    self.skip_line();
+                }
+            }
+            if !self.separator() && !self.at_end() {
+                self.trailing(start);
+                self.skip_line();
+                self.blank();
             }
         }
 
-        // remove labels from code
-        let code = labeled_instrs
+        if self.problems.is_empty() {
+            return Ok(lines);
+        }
+
+        // convert all offsets at once, to keep to a single pass over the input
+        let offsets: Vec<(usize, usize)> = self.problems.iter().map(|p| p.1).collect();
+        let spans = Span::from_offsets(self.input, &offsets);
+        let diagnostics = self.problems
             .into_iter()
-            .filter_map(|x| x.into())
+            .zip(spans)
+            .map(|((d, _), span)| Diagnostic { span, ..d })
             .collect();
+        bail!(ErrorKind::Diagnostics(diagnostics))
+    }
+}
+
+pub fn parse(input: &str) -> Result<Program> {
+    let labeled_instrs = Parser::new(input).lines()?;
+    let mut labels = HashMap::new();
 
-        Ok((code, labels))
+    let mut acc = 0;
+    // insert labels with the correct pointer
+    for (i, (instr, _)) in labeled_instrs.iter().enumerate() {
+        if let Line::Label(ref val) = *instr {
+            labels.insert(val.clone(), i - acc);
+            acc += 1;
+        }
     }
+
+    // remove labels from code, keeping the position of each instruction
+    let mut code = Vec::with_capacity(labeled_instrs.len() - acc);
+    let mut offsets = Vec::with_capacity(labeled_instrs.len() - acc);
+    for (instr, pos) in labeled_instrs {
+        if let Line::Instr(i) = instr {
+            code.push(i);
+            offsets.push(pos);
+        }
+    }
+    let spans = Span::from_offsets(input, &offsets);
+
+    Ok(Program {
+        code,
+        labels,
+        spans,
+    })
 }
 
 #[cfg(test)]
-mod parser {
-    use super::parser_impl::parse;
+mod tests {
+    use super::{closest, distance, parse, Diagnostic};
+    use errors::ErrorKind;
     use instructions::Instruction as ins;
     use std::collections::HashMap;
 
@@ -292,18 +732,18 @@ mod parser {
         ($func: ident, $input: expr, $instr: expr, $labels: expr) => (
             #[test]
             fn $func() {
-                let (instr, labels) = parse($input).unwrap();
-                assert_eq!(instr, $instr);
-                assert_eq!(labels, $labels);
+                let program = parse($input).unwrap();
+                assert_eq!(program.code, $instr);
+                assert_eq!(program.labels, $labels);
             }
         );
         ($attr: meta, $func: ident, $input: expr, $instr: expr, $labels: expr) => (
             #[$attr]
             #[test]
             fn $func() {
-                let (instr, labels) = parse($input).unwrap();
-                assert_eq!(instr, $instr);
-                assert_eq!(labels, $labels);
+                let program = parse($input).unwrap();
+                assert_eq!(program.code, $instr);
+                assert_eq!(program.labels, $labels);
             }
         );
     }
@@ -377,6 +817,21 @@ mod parser {
         [ins::Start, ins::Stop]
     );
     test!(sep_ins_nl_nl, "start\n\nstop", [ins::Start, ins::Stop]);
+    test!(sep_ins_crlf, "start\r\n\r\nstop\r\n", [ins::Start, ins::Stop]);
+    test!(
+        label_same_line,
+        "loop: pushg 0\na:b:\tstop",
+        [ins::Pushg(0), ins::Stop],
+        [("loop".to_string(), 0), ("a".to_string(), 1), ("b".to_string(), 1)]
+            .iter().cloned().collect::<HashMap<_, _>>()
+    );
+    test!(
+        label_comment,
+        "l: // loop\r\nstart\r\nm:// end",
+        [ins::Start],
+        [("l".to_string(), 0), ("m".to_string(), 1)].iter().cloned().collect::<HashMap<_, _>>()
+    );
+    test_fail!(label_after_instr, "start l:");
     test!(sep_ins_nl_sp_nl, "start\n \nstop", [ins::Start, ins::Stop]);
 
     test_fail!(sep_arg_g, "pushg1", [ins::Pushg(1)]);
@@ -384,4 +839,87 @@ mod parser {
     test_fail!(sep_arg_i, "pushi2", [ins::Pushi(2)]);
     test!(sep_arg_i_sp_pos, "pushi 2", [ins::Pushi(2)]);
     test!(sep_arg_i_sp_neg, "pushi -2", [ins::Pushi(-2)]);
+
+    fn diagnostics(input: &str) -> Vec<Diagnostic> {
+        match *parse(input).unwrap_err().kind() {
+            ErrorKind::Diagnostics(ref d) => d.clone(),
+            ref e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn every_error_reported() {
+        let d = diagnostics("start\n\tstroeg 1\npushi x\nstart stop\npushi2\n");
+        let lines: Vec<usize> = d.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, [2, 3, 4, 5]);
+        assert_eq!(d[0].message, "unknown instruction `stroeg`");
+        assert_eq!(d[0].help, Some("did you mean `storeg`?".to_string()));
+        assert_eq!(d[1].expected, Some("an integer after `pushi`".to_string()));
+        assert_eq!(d[2].message, "unexpected `stop`");
+        assert_eq!(d[3].help, Some("did you mean `pushi 2`?".to_string()));
+    }
+
+    #[test]
+    fn snippet() {
+        let d = diagnostics("start\n\tstroeg 1\n").remove(0);
+        assert_eq!((d.span.col, d.span.end_col), (2, 8));
+        let text = d.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[2], "2 | \tstroeg 1");
+        assert!(lines[3].starts_with("  | \t") && lines[3].contains("^^^^^^"));
+    }
+
+    #[test]
+    fn edit_distance() {
+        assert_eq!(distance("stroeg", "storeg"), 1);
+        assert_eq!(distance("pushi", "pushi"), 0);
+        assert_eq!(distance("", "add"), 3);
+        assert_eq!(distance("writei", "writes"), 1);
+        assert_eq!(distance("ab", "ba"), 1);
+        assert_eq!(distance("ca", "abc"), 3);
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(closest("psuhi"), Some("pushi"));
+        assert_eq!(closest("STROEG"), Some("storeg"));
+        // as close to `pusha`, `pushg`, `pushi`, `pushn` and `pushs`
+        assert_eq!(closest("pushj"), None);
+        assert_eq!(closest("xyz"), None);
+    }
+
+    test!(
+        string_escapes,
+        "pushs \"a\\\"b\\\\\"\nerr \"\\n\\t\\u{e9}\\u{1F600}\"",
+        [ins::Pushs("a\"b\\".to_string()), ins::Err("\n\t\u{e9}\u{1F600}".to_string())]
+    );
+    test_fail!(string_unknown_escape, "pushs \"\\q\"");
+    test_fail!(string_bad_unicode, "pushs \"\\u{110000}\"");
+
+    #[test]
+    fn strings_print_as_they_parse() {
+        let values = ["a\"b", "\\", "line\nnext\ttab", "\r\u{0}é"];
+        for value in values.iter() {
+            let text = ins::Pushs(value.to_string()).to_string();
+            assert_eq!(parse(&text).unwrap().code, [ins::Pushs(value.to_string())]);
+        }
+        assert_eq!(ins::Err("a\n\"".to_string()).to_string(), "err \"a\\n\\\"\"");
+    }
+
+    #[test]
+    fn large_program() {
+        let mut input = String::from("start\n");
+        for i in 0..200_000 {
+            input.push_str(&format!("l{}:\n\tpushi {}\n\tpop 1 // comment\n", i, i));
+        }
+        input.push_str("stop\n");
+        // `pop` is not implemented, so every line with it is reported
+        assert_eq!(diagnostics(&input).len(), 200_000);
+
+        let input = input.replace("\tpop 1", "\tadd");
+        let program = parse(&input).unwrap();
+        assert_eq!(program.code.len(), 400_002);
+        assert_eq!(program.labels["l199999"], 399_999);
+        assert_eq!(program.spans[400_001].line, 600_002);
+    }
 }
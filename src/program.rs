@@ -0,0 +1,185 @@
+//! Loaded program representation
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use errors::*;
+use instructions::Instruction;
+use parser;
+use vmb;
+
+/// A region of the source file, as 1-based line and column numbers
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub end_line: usize,
+    pub end_col: usize,
+}
+
+impl Span {
+    /// Convert sorted pairs of byte offsets to spans in a single pass over `input`
+    pub fn from_offsets(input: &str, offsets: &[(usize, usize)]) -> Vec<Span> {
+        let mut positions = Vec::with_capacity(offsets.len() * 2);
+        for &(start, end) in offsets {
+            positions.push(start);
+            positions.push(end);
+        }
+
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        order.sort_by_key(|&i| positions[i]);
+
+        let mut line_col = vec![(1, 1); positions.len()];
+        let mut chars = input.char_indices().peekable();
+        let (mut line, mut col) = (1, 1);
+        for i in order {
+            while let Some(&(pos, c)) = chars.peek() {
+                if pos >= positions[i] {
+                    break;
+                }
+                if c == '\n' {
+                    line += 1;
+                    col = 1;
+                } else {
+                    col += 1;
+                }
+                chars.next();
+            }
+            line_col[i] = (line, col);
+        }
+
+        line_col
+            .chunks(2)
+            .map(|p| Span {
+                line: (p[0]).0,
+                col: (p[0]).1,
+                end_line: (p[1]).0,
+                end_col: (p[1]).1,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line({}), col({})", self.line, self.col)
+    }
+}
+
+/// Character encoding of a `.vm` source file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Encoding {
+    /// `UTF-8` if the file is valid `UTF-8`, `Latin-1` otherwise
+    #[default]
+    Auto,
+    Utf8,
+    /// ISO-8859-1, where each byte is the character with the same code
+    Latin1,
+}
+
+impl Encoding {
+    /// Decode the contents of a source file
+    pub fn decode(self, buffer: Vec<u8>) -> Result<String> {
+        let text = match self {
+            Encoding::Latin1 => buffer.iter().map(|&b| b as char).collect(),
+            Encoding::Utf8 => String::from_utf8(buffer)
+                .chain_err(|| "The file is not valid UTF-8, try `--encoding latin-1`")?,
+            Encoding::Auto => match String::from_utf8(buffer) {
+                Ok(text) => text,
+                Err(e) => return Encoding::Latin1.decode(e.into_bytes()),
+            },
+        };
+
+        // Editors on Windows may start the file with a byte order mark
+        match text.strip_prefix('\u{feff}') {
+            Some(rest) => Ok(rest.to_string()),
+            None => Ok(text),
+        }
+    }
+}
+
+/// The result of parsing a `vm` source file
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    /// Instructions, without labels
+    pub code: Vec<Instruction>,
+    /// Label name to instruction index
+    pub labels: HashMap<String, usize>,
+    /// Source location of each instruction in `code`
+    pub spans: Vec<Span>,
+}
+
+impl Program {
+    /// Read a program from a `.vm` source file in the given encoding, or a `.vmb` binary file
+    pub fn load<P: AsRef<Path>>(path: P, encoding: Encoding) -> Result<Program> {
+        // Open file
+        let mut f = File::open(&path)
+            .chain_err(|| format!("Failed to open file '{}'", path.as_ref().display()))?;
+
+        // Load file to memory
+        let mut buffer = Vec::new();
+        f.read_to_end(&mut buffer)
+            .chain_err(|| format!("Unable to Read file '{}'", path.as_ref().display()))?;
+
+        if vmb::is_vmb(&buffer) {
+            return vmb::decode(&buffer)
+                .chain_err(|| format!("Unable to Load file '{}'", path.as_ref().display()));
+        }
+
+        let text = encoding
+            .decode(buffer)
+            .chain_err(|| format!("Unable to Read file '{}'", path.as_ref().display()))?;
+
+        // Parse the file
+        parser::parse(&text)
+            .chain_err(|| format!("Unable to Parse file '{}'", path.as_ref().display()))
+    }
+}
+
+/// Print the program as canonical `.vm` source
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut labels: Vec<(&usize, &String)> = self.labels.iter().map(|(k, v)| (v, k)).collect();
+        labels.sort();
+        let mut labels = labels.into_iter().peekable();
+
+        for (i, instr) in self.code.iter().enumerate() {
+            while let Some((_, name)) = labels.next_if(|&(&addr, _)| addr <= i) {
+                writeln!(f, "{}:", name)?;
+            }
+            writeln!(f, "\t{}", instr)?;
+        }
+        for (_, name) in labels {
+            writeln!(f, "{}:", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoding;
+
+    #[test]
+    fn decode_utf8() {
+        let text = "pushs \"número\"";
+        assert_eq!(Encoding::Auto.decode(text.as_bytes().to_vec()).unwrap(), text);
+        assert_eq!(Encoding::Utf8.decode(text.as_bytes().to_vec()).unwrap(), text);
+    }
+
+    #[test]
+    fn decode_latin1() {
+        let bytes = b"pushs \"n\xfamero\"".to_vec();
+        assert_eq!(Encoding::Auto.decode(bytes.clone()).unwrap(), "pushs \"número\"");
+        assert_eq!(Encoding::Latin1.decode(bytes.clone()).unwrap(), "pushs \"número\"");
+        assert!(Encoding::Utf8.decode(bytes).is_err());
+    }
+
+    #[test]
+    fn decode_bom() {
+        assert_eq!(Encoding::Auto.decode(b"\xef\xbb\xbfstart".to_vec()).unwrap(), "start");
+    }
+}
@@ -0,0 +1,191 @@
+//! The line editor of the debug CLI
+//!
+//! Lines can be edited and recalled from a history that is kept between
+//! sessions, and `Tab` completes command names, labels and registers.
+
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::path::PathBuf;
+
+use rustyline;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::Validator;
+use rustyline::{Config, Context, Editor, Helper};
+
+use errors::*;
+
+use commands;
+use expr;
+
+/// The history file, in the home directory
+const HISTORY_FILE: &str = ".pl-vm_history";
+
+/// Number of lines kept in the history
+const HISTORY_SIZE: usize = 1000;
+
+/// Commands that take a location
+const LOCATIONS: &[&str] = &["b", "break", "u", "until"];
+
+/// Commands that take an expression
+const EXPRESSIONS: &[&str] = &[
+    "b", "break", "condition", "p", "print", "push", "rwatch", "set", "watch", "x",
+];
+
+/// Completes the words of a command
+struct Completion {
+    labels: Vec<String>,
+}
+
+impl Completion {
+    /// Where the word before the end of `line` starts, and the ways it can end
+    fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        let start = |word: fn(char) -> bool| line.rfind(|c| !word(c)).map_or(0, |i| i + 1);
+
+        // command names can have a '-', expressions use it to subtract
+        let first = start(|c| c.is_ascii_alphanumeric() || c == '-');
+        if line[..first].trim().is_empty() {
+            let word = &line[first..];
+            let names = commands::NAMES.iter().filter(|name| name.starts_with(word));
+            return (first, names.map(|name| name.to_string()).collect());
+        }
+
+        let start = start(|c| c.is_ascii_alphanumeric() || c == '_');
+        let (word, head) = (&line[start..], &line[..start]);
+        let mut args = head.split_whitespace();
+        let cmd = args.next().unwrap_or_default().to_lowercase();
+        let is_location = LOCATIONS.contains(&cmd.as_ref()) && args.next().is_none();
+
+        let mut res: Vec<String> = if is_location {
+            self.labels.clone()
+        } else if EXPRESSIONS.contains(&cmd.as_ref()) {
+            let names = expr::NAMES.iter().map(|name| name.to_string());
+            names.chain(self.labels.iter().cloned()).collect()
+        } else {
+            Vec::new()
+        };
+        res.retain(|name| name.starts_with(word));
+        res.sort();
+        res.dedup();
+        (start, res)
+    }
+}
+
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context)
+                -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(&line[..pos]))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
+
+/// Reads the commands typed by the user
+pub struct Prompt {
+    editor: Editor<Completion, FileHistory>,
+    /// Input that is not a terminal is read as plain lines
+    interactive: bool,
+    /// Where the history is saved
+    history: Option<PathBuf>,
+}
+
+impl Prompt {
+    pub fn new() -> Result<Self> {
+        let config = Config::builder()
+            .max_history_size(HISTORY_SIZE)
+            .and_then(|c| c.history_ignore_dups(true))
+            .chain_err(|| "Unable to configure the debug prompt")?
+            .auto_add_history(false)
+            .build();
+        let mut editor = Editor::with_config(config).chain_err(|| "Unable to start the debug prompt")?;
+        editor.set_helper(Some(Completion { labels: Vec::new() }));
+
+        // scripts piped to the debugger stay out of the history
+        let interactive = io::stdin().is_terminal();
+        let history = match env::var_os("HOME") {
+            Some(ref home) if interactive => Some(PathBuf::from(home).join(HISTORY_FILE)),
+            _ => None,
+        };
+        if let Some(ref path) = history {
+            // there is no history yet the first time
+            let _ = editor.load_history(path);
+        }
+        Ok(Prompt { editor, interactive, history })
+    }
+
+    /// Set the labels of the program, to complete locations
+    pub fn set_labels(&mut self, labels: Vec<String>) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.labels = labels;
+        }
+    }
+
+    /// Read a line, `None` at the end of the input
+    pub fn readline(&mut self, prompt: &str) -> Result<Option<String>> {
+        if !self.interactive {
+            print!("{}", prompt);
+            io::stdout().flush().expect("Could not flush stdout");
+            let mut buf = String::new();
+            let read = io::stdin().read_line(&mut buf).chain_err(|| "Error reading line")?;
+            return Ok(if read == 0 { None } else { Some(buf) });
+        }
+        loop {
+            match self.editor.readline(prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        self.remember(&line);
+                    }
+                    return Ok(Some(line));
+                }
+                // Ctrl-C drops the line being typed
+                Err(ReadlineError::Interrupted) => {}
+                Err(ReadlineError::Eof) => return Ok(None),
+                Err(e) => return Err(e).chain_err(|| "Error reading line"),
+            }
+        }
+    }
+
+    /// Add a line to the history, saving it right away in case the session is killed
+    fn remember(&mut self, line: &str) {
+        let _ = self.editor.add_history_entry(line);
+        if let Some(ref path) = self.history {
+            // losing the history is not worth stopping the session
+            let _ = self.editor.save_history(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Completion;
+
+    fn complete(line: &str) -> (usize, Vec<String>) {
+        let completion = Completion { labels: vec!["loop".to_string(), "start".to_string()] };
+        completion.candidates(line)
+    }
+
+    #[test]
+    fn candidates() {
+        assert_eq!(complete("fi"), (0, vec!["finish".to_string()]));
+        assert_eq!(complete("  reverse-"),
+                   (2, vec!["reverse-continue".to_string(), "reverse-step".to_string()]));
+        assert_eq!(complete("b l"), (2, vec!["loop".to_string()]));
+        assert_eq!(complete("until "), (6, vec!["loop".to_string(), "start".to_string()]));
+        assert_eq!(complete("p gp[0]-st").1, vec!["stack", "start", "steps", "str"]);
+        assert_eq!(complete("break loop if s").1, vec!["sp", "stack", "start", "steps", "str"]);
+        assert!(complete("step l").1.is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+//! Execution statistics
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use instructions::Instruction;
+
+/// Counters collected while the `vm` runs
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Number of instructions executed
+    pub steps: u64,
+    /// Number of times the instruction at each code address was executed
+    pub counts: Vec<u64>,
+    /// Deepest the operand stack has been
+    pub max_stack: usize,
+    /// Deepest the call stack has been
+    pub max_calls: usize,
+    /// Number of strings added to the string area
+    pub strings: u64,
+    /// Current size of the string area in bytes
+    pub heap: usize,
+    /// Largest size of the string area in bytes
+    pub heap_peak: usize,
+    /// Total cost of the instructions executed
+    pub cost: u64,
+    /// Time spent running
+    pub elapsed: Duration,
+}
+
+impl Stats {
+    /// Count the instruction at `pc`, about to be executed
+    pub fn record(&mut self, pc: usize) {
+        if pc >= self.counts.len() {
+            self.counts.resize(pc + 1, 0);
+        }
+        self.steps += 1;
+        self.counts[pc] += 1;
+    }
+
+    /// Track the size of the stacks after an instruction
+    pub fn observe(&mut self, stack: usize, calls: usize) {
+        self.max_stack = self.max_stack.max(stack);
+        self.max_calls = self.max_calls.max(calls);
+    }
+
+    /// Track a string added to the string area
+    pub fn alloc(&mut self, bytes: usize) {
+        self.strings += 1;
+        self.heap += bytes;
+        self.heap_peak = self.heap_peak.max(self.heap);
+    }
+
+    /// Print the report to `stderr`, naming the instructions from `code`
+    pub fn print(&self, code: &[Instruction]) {
+        let stderr = io::stderr();
+        let _ = self.write(code, &mut stderr.lock());
+    }
+
+    /// Write the report, naming the instructions from `code`
+    fn write<W: Write>(&self, code: &[Instruction], out: &mut W) -> io::Result<()> {
+        let secs = self.elapsed.as_secs_f64();
+        let rate = if secs > 0.0 {
+            self.steps as f64 / secs
+        } else {
+            0.0
+        };
+
+        let mut by_name = HashMap::new();
+        for (instr, &count) in code.iter().zip(&self.counts) {
+            if count > 0 {
+                *by_name.entry(instr.name()).or_insert(0) += count;
+            }
+        }
+        let mut counts: Vec<_> = by_name.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        writeln!(out, "\n--- statistics ---")?;
+        writeln!(out, "{:24}{}", "instructions executed", self.steps)?;
+        writeln!(out, "{:24}{}", "total cost", self.cost)?;
+        writeln!(out, "{:24}{:.6}s", "time", secs)?;
+        writeln!(out, "{:24}{:.0}", "instructions per second", rate)?;
+        writeln!(out, "{:24}{}", "max stack depth", self.max_stack)?;
+        writeln!(out, "{:24}{}", "max call depth", self.max_calls)?;
+        writeln!(out, "{:24}{}", "strings allocated", self.strings)?;
+        writeln!(out, "{:24}{} bytes", "heap peak", self.heap_peak)?;
+        writeln!(out, "instructions by opcode:")?;
+        for (name, count) in counts {
+            writeln!(out, "\t{:20}{}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Stats;
+    use instructions::Instruction as ins;
+
+    #[test]
+    fn counters() {
+        let mut stats = Stats::default();
+        for &pc in &[0, 2, 2] {
+            stats.record(pc);
+        }
+        stats.observe(4, 1);
+        stats.observe(2, 3);
+        stats.alloc(5);
+        stats.alloc(3);
+        assert_eq!((stats.steps, stats.counts.clone()), (3, vec![1, 0, 2]));
+        assert_eq!((stats.max_stack, stats.max_calls), (4, 3));
+        assert_eq!((stats.strings, stats.heap_peak), (2, 8));
+    }
+
+    #[test]
+    fn report() {
+        let stats = Stats {
+            steps: 4,
+            counts: vec![1, 2, 1],
+            cost: 9,
+            elapsed: Duration::from_secs(2),
+            ..Stats::default()
+        };
+        let mut out = Vec::new();
+        stats.write(&[ins::Pushi(1), ins::Pushi(2), ins::Add], &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[2], "instructions executed   4");
+        assert_eq!(lines[3], "total cost              9");
+        assert_eq!(lines[5], "instructions per second 2");
+        // the most executed first, then by name
+        assert_eq!(&lines[11..], ["\tpushi               3", "\tadd                 1"]);
+    }
+}
@@ -0,0 +1,592 @@
+//! Translation of `vm` programs to C
+//!
+//! Every instruction becomes a call to a C function that behaves like the
+//! interpreter, labelled so that jumps are `goto`s. Calls and returns go
+//! through a `switch` over every code address, since their target is only
+//! known when the program runs.
+
+use std::fmt::Write;
+
+use bytecode::{self, Op};
+use errors::*;
+use program::Program;
+
+/// Runtime of the generated programs
+const RUNTIME: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+enum { INTEGER, ADDRESS };
+
+typedef struct {
+    int tag;
+    union {
+        int32_t i;
+        size_t a;
+    } v;
+} operand;
+
+typedef struct {
+    size_t pc;
+    size_t fp;
+} frame;
+
+typedef struct {
+    char *s;
+    size_t len;
+} string;
+
+static operand *stack;
+static size_t sp, stack_cap;
+static frame *calls;
+static size_t csp, calls_cap;
+static string *strings;
+static size_t nstrings, strings_cap;
+static size_t pc, fp;
+static const size_t gp = 0;
+
+/* The interpreter stops with a message on stderr on these errors */
+static inline void panic(const char *msg) {
+    fflush(stdout);
+    fprintf(stderr, "%s\n", msg);
+    exit(101);
+}
+
+static inline void *grow(void *p, size_t *cap, size_t size) {
+    *cap = *cap ? *cap * 2 : 64;
+    p = realloc(p, *cap * size);
+    if (!p) {
+        panic("memory allocation failed");
+    }
+    return p;
+}
+
+static inline void check_index(size_t index, size_t len) {
+    char msg[128];
+    if (index >= len) {
+        snprintf(msg, sizeof msg, "index out of bounds: the len is %zu but the index is %zu", len, index);
+        panic(msg);
+    }
+}
+
+static inline void red(const char *s, size_t len) {
+    fputs("\033[31m", stdout);
+    fwrite(s, 1, len, stdout);
+    fputs("\033[0m", stdout);
+}
+
+/* Report a runtime error as the interpreter does */
+static inline void fail(const char *title, const char *detail) {
+    putchar('\n');
+    red(title, strlen(title));
+    if (detail) {
+        printf(" %s", detail);
+    }
+    putchar('\n');
+    exit(1);
+}
+
+static inline void illegal(const char *detail) {
+    fail("Illegal Operand:", detail);
+}
+
+static inline const char *show(operand o, char *buf, size_t size) {
+    if (o.tag == INTEGER) {
+        snprintf(buf, size, "%2di", o.v.i);
+    } else {
+        snprintf(buf, size, "%2zua", o.v.a);
+    }
+    return buf;
+}
+
+static inline void illegal_pair(const char *name, operand x, const char *op, operand y) {
+    char a[32], b[32], msg[128];
+    snprintf(msg, sizeof msg, "Operand::%s => %s %s %s", name, show(x, a, sizeof a), op, show(y, b, sizeof b));
+    illegal(msg);
+}
+
+static inline void push(operand o) {
+    if (sp == stack_cap) {
+        stack = grow(stack, &stack_cap, sizeof *stack);
+    }
+    stack[sp++] = o;
+}
+
+static inline void push_int(int32_t i) {
+    operand o;
+    o.tag = INTEGER;
+    o.v.i = i;
+    push(o);
+}
+
+static inline void push_address(size_t a) {
+    operand o;
+    o.tag = ADDRESS;
+    o.v.a = a;
+    push(o);
+}
+
+static inline operand pop(void) {
+    if (sp == 0) {
+        fail("Segmentation Fault:", "Stack is empty");
+    }
+    return stack[--sp];
+}
+
+static inline size_t alloc_string(const char *s, size_t len) {
+    if (nstrings == strings_cap) {
+        strings = grow(strings, &strings_cap, sizeof *strings);
+    }
+    strings[nstrings].s = malloc(len + 1);
+    if (!strings[nstrings].s) {
+        panic("memory allocation failed");
+    }
+    memcpy(strings[nstrings].s, s, len);
+    strings[nstrings].len = len;
+    return nstrings++;
+}
+
+static inline int32_t wrap(int64_t v) {
+    return (int32_t)(uint32_t)(uint64_t)v;
+}
+
+/* Operand::add, with `n` from the top of the stack */
+static inline operand add(operand n, operand a) {
+    operand r;
+    if (n.tag == INTEGER && a.tag == ADDRESS) {
+        r.tag = ADDRESS;
+        r.v.a = (size_t)(int64_t)wrap((int64_t)(int32_t)(uint32_t)a.v.a + n.v.i);
+    } else if (n.tag == INTEGER && a.tag == INTEGER) {
+        r.tag = INTEGER;
+        r.v.i = wrap((int64_t)n.v.i + a.v.i);
+    } else {
+        illegal_pair("add", n, "+", a);
+    }
+    return r;
+}
+
+static inline operand sub(operand n, operand a) {
+    char msg[128];
+    operand r;
+    if (n.tag == INTEGER && a.tag == ADDRESS) {
+        if (n.v.i < 0 || a.v.a < (size_t)n.v.i) {
+            snprintf(msg, sizeof msg, "Operand::sub => %d - %zu", n.v.i, a.v.a);
+            illegal(msg);
+        }
+        r.tag = ADDRESS;
+        r.v.a = a.v.a - (size_t)n.v.i;
+    } else if (n.tag == INTEGER && a.tag == INTEGER) {
+        r.tag = INTEGER;
+        r.v.i = wrap((int64_t)n.v.i - a.v.i);
+    } else {
+        illegal_pair("sub", n, "-", a);
+    }
+    return r;
+}
+
+static inline operand mul(operand n, operand m) {
+    operand r;
+    if (n.tag != INTEGER || m.tag != INTEGER) {
+        illegal_pair("mul", m, "*", n);
+    }
+    r.tag = INTEGER;
+    r.v.i = wrap((int64_t)m.v.i * n.v.i);
+    return r;
+}
+
+static inline operand divide(operand n, operand m) {
+    operand r;
+    if (n.tag == INTEGER && n.v.i == 0) {
+        fail("Division By Zero", NULL);
+    }
+    if (n.tag != INTEGER || m.tag != INTEGER) {
+        illegal_pair("div", m, "/", n);
+    }
+    if (m.v.i == INT32_MIN && n.v.i == -1) {
+        panic("attempt to divide with overflow");
+    }
+    r.tag = INTEGER;
+    r.v.i = m.v.i / n.v.i;
+    return r;
+}
+
+static inline operand module(operand n, operand m) {
+    operand r;
+    if (n.tag != INTEGER || m.tag != INTEGER) {
+        illegal_pair("mod", m, "%", n);
+    }
+    if (n.v.i == 0) {
+        panic("attempt to calculate the remainder with a divisor of zero");
+    }
+    if (m.v.i == INT32_MIN && n.v.i == -1) {
+        panic("attempt to calculate the remainder with overflow");
+    }
+    r.tag = INTEGER;
+    r.v.i = m.v.i % n.v.i;
+    return r;
+}
+
+enum { EQUAL, INF, INFEQ, SUP, SUPEQ };
+
+static inline operand compare(int cmp, operand n, operand m) {
+    static const char *const names[] = {"equal", "inf", "infeq", "sup", "supeq"};
+    static const char *const ops[] = {"==", "<", "<=", ">", ">="};
+    operand r;
+    if (n.tag != INTEGER || m.tag != INTEGER) {
+        illegal_pair(names[cmp], m, ops[cmp], n);
+    }
+    r.tag = INTEGER;
+    switch (cmp) {
+    case EQUAL: r.v.i = m.v.i == n.v.i; break;
+    case INF: r.v.i = m.v.i < n.v.i; break;
+    case INFEQ: r.v.i = m.v.i <= n.v.i; break;
+    case SUP: r.v.i = m.v.i > n.v.i; break;
+    default: r.v.i = m.v.i >= n.v.i; break;
+    }
+    return r;
+}
+
+static inline void binary(operand (*op)(operand, operand)) {
+    operand n = pop();
+    operand m = pop();
+    push(op(n, m));
+}
+
+static inline void binary_compare(int cmp) {
+    operand n = pop();
+    operand m = pop();
+    push(compare(cmp, n, m));
+}
+
+static inline void pushn(int32_t n) {
+    int32_t i;
+    for (i = 0; i < n; i++) {
+        push_int(0);
+    }
+}
+
+static inline void pushg(size_t n) {
+    operand v;
+    check_index(gp + n, sp);
+    v = stack[gp + n];
+    push(v);
+}
+
+static inline void storeg(size_t n) {
+    operand v = pop();
+    check_index(gp + n, sp);
+    stack[gp + n] = v;
+}
+
+static inline void loadn(void) {
+    operand n = pop();
+    operand a = pop();
+    operand r = add(n, a);
+    if (r.tag == ADDRESS) {
+        operand v;
+        check_index(r.v.a, sp);
+        v = stack[r.v.a];
+        push(v);
+    }
+}
+
+static inline void storen(void) {
+    operand v = pop();
+    operand n = pop();
+    operand a = pop();
+    operand r = add(n, a);
+    if (r.tag == ADDRESS) {
+        check_index(r.v.a, sp);
+        stack[r.v.a] = v;
+    }
+}
+
+static inline void writei(void) {
+    operand v = pop();
+    if (v.tag != INTEGER) {
+        illegal("writei: Not an Integer");
+    }
+    printf("%d", v.v.i);
+    fflush(stdout);
+}
+
+static inline void writes(void) {
+    operand v = pop();
+    if (v.tag != ADDRESS) {
+        illegal("writes: Must be address to write string");
+    }
+    check_index(v.v.a, nstrings);
+    fwrite(strings[v.v.a].s, 1, strings[v.v.a].len, stdout);
+    fflush(stdout);
+}
+
+static inline int is_space(char c) {
+    return c == ' ' || (c >= '\t' && c <= '\r');
+}
+
+static inline void read(void) {
+    char *line = NULL;
+    size_t len = 0, cap = 0, start = 0;
+    int c;
+    while ((c = getchar()) != EOF) {
+        if (len == cap) {
+            line = grow(line, &cap, 1);
+        }
+        line[len++] = (char)c;
+        if (c == '\n') {
+            break;
+        }
+    }
+    while (len > start && is_space(line[len - 1])) {
+        len--;
+    }
+    while (start < len && is_space(line[start])) {
+        start++;
+    }
+    push_address(alloc_string(line ? line + start : "", len - start));
+    free(line);
+}
+
+static inline int parse_i32(const string *s, int32_t *out) {
+    size_t i = 0;
+    int64_t v = 0;
+    int neg = 0;
+    if (s->len > 0 && (s->s[0] == '+' || s->s[0] == '-')) {
+        neg = s->s[0] == '-';
+        i = 1;
+    }
+    if (i == s->len) {
+        return 0;
+    }
+    for (; i < s->len; i++) {
+        if (s->s[i] < '0' || s->s[i] > '9') {
+            return 0;
+        }
+        v = v * 10 + (s->s[i] - '0');
+        if (v > 2147483648LL) {
+            return 0;
+        }
+    }
+    v = neg ? -v : v;
+    if (v > INT32_MAX) {
+        return 0;
+    }
+    *out = (int32_t)v;
+    return 1;
+}
+
+static inline void atoi_(void) {
+    operand v = pop();
+    int32_t i;
+    if (v.tag != ADDRESS) {
+        illegal("atoi => Must be an address to write string");
+    }
+    check_index(v.v.a, nstrings);
+    if (!parse_i32(&strings[v.v.a], &i)) {
+        illegal("Value is not a valid Integer");
+    }
+    push_int(i);
+}
+
+static inline void call(void) {
+    operand v = pop();
+    if (v.tag != ADDRESS) {
+        illegal("call => Not an Address");
+    }
+    if (csp == calls_cap) {
+        calls = grow(calls, &calls_cap, sizeof *calls);
+    }
+    calls[csp].pc = pc;
+    calls[csp].fp = fp;
+    csp++;
+    fp = sp;
+    pc = v.v.a;
+}
+
+static inline void ret(void) {
+    if (csp == 0) {
+        fail("Segmentation Fault:", "Call stack is empty");
+    }
+    csp--;
+    pc = calls[csp].pc + 1;
+    fp = calls[csp].fp;
+}
+
+/* Whether the jump is taken */
+static inline int jz(void) {
+    operand v = pop();
+    if (v.tag == INTEGER && v.v.i == 0) {
+        return 1;
+    }
+    if (v.tag == INTEGER && v.v.i == 1) {
+        return 0;
+    }
+    illegal("jz => Not an Integer(0|1)");
+    return 0;
+}
+
+static inline void err(size_t i) {
+    putchar('\n');
+    red(strings[i].s, strings[i].len);
+    putchar('\n');
+    exit(1);
+}
+
+static inline void stop(void) {
+    putchar('\n');
+    exit(0);
+}
+"#;
+
+/// A C string literal with the bytes of `s`
+fn c_str(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for &b in s.as_bytes() {
+        match b {
+            b'"' => res.push_str("\\\""),
+            b'\\' => res.push_str("\\\\"),
+            // `?` would start a trigraph
+            b'?' => res.push_str("\\?"),
+            0x20..=0x7e => res.push(b as char),
+            _ => {
+                let _ = write!(res, "\\{:03o}", b);
+            }
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// C source of a single instruction, at code address `pc`
+fn statement(op: Op, pc: usize) -> String {
+    match op {
+        Op::Pushi(i32::MIN) => "push_int(INT32_MIN);".to_string(),
+        Op::Pushi(val) => format!("push_int({});", val),
+        Op::Pushn(val) => format!("pushn({});", val),
+        Op::Pushg(val) => format!("pushg({}u);", val),
+        Op::Pushs(val) | Op::Pusha(val) => format!("push_address({}u);", val),
+        Op::Pushgp => "push_address(gp);".to_string(),
+        Op::Call => format!("pc = {}; call(); goto dispatch;", pc),
+        Op::Return => "ret(); goto dispatch;".to_string(),
+        Op::Start => "fp = sp;".to_string(),
+        Op::Nop => ";".to_string(),
+        Op::Stop => "stop();".to_string(),
+        Op::Loadn => "loadn();".to_string(),
+        Op::Writei => "writei();".to_string(),
+        Op::Writes => "writes();".to_string(),
+        Op::Read => "read();".to_string(),
+        Op::Atoi => "atoi_();".to_string(),
+        Op::Padd | Op::Add => "binary(add);".to_string(),
+        Op::Sub => "binary(sub);".to_string(),
+        Op::Mul => "binary(mul);".to_string(),
+        Op::Div => "binary(divide);".to_string(),
+        Op::Mod => "binary(module);".to_string(),
+        Op::Storeg(val) => format!("storeg({}u);", val),
+        Op::Storen => "storen();".to_string(),
+        Op::Equal => "binary_compare(EQUAL);".to_string(),
+        Op::Inf => "binary_compare(INF);".to_string(),
+        Op::Infeq => "binary_compare(INFEQ);".to_string(),
+        Op::Sup => "binary_compare(SUP);".to_string(),
+        Op::Supeq => "binary_compare(SUPEQ);".to_string(),
+        Op::Jump(addr) => format!("goto I_{};", addr),
+        Op::Jz(addr) => format!("if (jz()) goto I_{};", addr),
+        Op::Err(val) => format!("err({}u);", val),
+        Op::GlobalAddr(..) | Op::LoadGlobal(..) | Op::StoreGlobalI(..) | Op::StoreGlobalG(..) => {
+            unreachable!("superinstructions are not compiled")
+        }
+    }
+}
+
+/// Translate a program to a self-contained C program
+pub fn translate(program: &Program) -> Result<String> {
+    let bytecode = bytecode::compile(program)?;
+    let len = bytecode.ops.len();
+
+    let mut out = String::new();
+    out.push_str("/* Generated by pl-vm to-c */\n\n");
+    out.push_str(RUNTIME);
+
+    // The string operands are the first strings of the string area
+    if !bytecode.constants.is_empty() {
+        out.push_str("\nstatic const string CONSTANTS[] = {\n");
+        for s in &bytecode.constants {
+            let _ = writeln!(out, "    {{{}, {}}},", c_str(s), s.len());
+        }
+        out.push_str("};\n");
+    }
+
+    out.push_str("\nint main(void) {\n");
+    if !bytecode.constants.is_empty() {
+        let _ = writeln!(out, "    size_t i;");
+        let _ = writeln!(out, "    for (i = 0; i < {}; i++) {{", bytecode.constants.len());
+        out.push_str("        alloc_string(CONSTANTS[i].s, CONSTANTS[i].len);\n    }\n");
+    }
+    out.push('\n');
+
+    // Calls and returns jump to addresses known when the program runs,
+    // so then every instruction is a target
+    let calls = bytecode
+        .ops
+        .iter()
+        .any(|op| matches!(*op, Op::Call | Op::Return));
+    let mut targets = vec![calls; len];
+    targets.push(false);
+    for op in &bytecode.ops {
+        if let Op::Jump(addr) | Op::Jz(addr) = *op {
+            targets[addr] = true;
+        }
+    }
+
+    for (pc, &op) in bytecode.ops.iter().enumerate() {
+        // The instruction as a comment, without ending it early
+        let instr = program.code[pc].to_string().replace("*/", "* /");
+        if targets[pc] {
+            let _ = write!(out, "I_{}: ", pc);
+        }
+        let _ = writeln!(out, "/* {} */", instr);
+        let _ = writeln!(out, "    {}", statement(op, pc));
+    }
+    if targets[len] {
+        let _ = writeln!(out, "I_{}:", len);
+    }
+    out.push_str("    fail(\"Segmentation Fault:\", \"Invalid PC counter\");\n");
+
+    if calls {
+        out.push_str("dispatch:\n    switch (pc) {\n");
+        for pc in 0..len {
+            let _ = writeln!(out, "    case {}: goto I_{};", pc, pc);
+        }
+        out.push_str("    default: fail(\"Segmentation Fault:\", \"Invalid PC counter\");\n    }\n");
+    }
+    out.push_str("    return 1;\n}\n");
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{c_str, translate};
+    use parser::parse;
+
+    #[test]
+    fn string_literal() {
+        assert_eq!(c_str("a\"b\\c?\n"), "\"a\\\"b\\\\c\\?\\012\"");
+    }
+
+    #[test]
+    fn labels_are_goto_targets() {
+        let c = translate(&parse("l:\npushi 1\njz l\nstop").unwrap()).unwrap();
+        assert!(c.contains("I_0: /* pushi 1 */\n    push_int(1);\n"));
+        assert!(c.contains("if (jz()) goto I_0;"));
+        assert!(!c.contains("dispatch"));
+    }
+
+    #[test]
+    fn calls_dispatch_on_pc() {
+        let c = translate(&parse("pusha f\ncall\nstop\nf:\nreturn").unwrap()).unwrap();
+        assert!(c.contains("pc = 1; call(); goto dispatch;"));
+        assert!(c.contains("case 3: goto I_3;"));
+    }
+}
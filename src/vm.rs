@@ -5,17 +5,22 @@ use std::io::Write;
 use std::fmt;
 
 use std::path::Path;
-use std::fs::File;
-use std::io::Read;
 
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-use errors;
 use errors::*;
 
-use parser;
+use program::{Encoding, Program, Span};
+
+use bytecode::{self, Op};
+use stats::Stats;
+use cost::CostTable;
 use instructions::Instruction;
-use commands::{Command, Status};
+use commands::Status;
+use debugger::Debugger;
+#[cfg(feature = "jit")]
+use jit::{self, Jit};
 
 /// The `vm` execution mode
 #[derive(Debug, Clone, Copy)]
@@ -24,9 +29,36 @@ pub enum Mode {
     Running,
 }
 
+/// Limits and settings for a `vm` run
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Maximum number of instructions to execute
+    pub max_steps: Option<u64>,
+    /// Maximum wall-clock time for `run`
+    pub timeout: Option<Duration>,
+    /// Collect and print execution statistics
+    pub stats: bool,
+    /// Print the total cost at the end of the run
+    pub print_cost: bool,
+    /// Instruction costs given by the user
+    pub costs: Option<CostTable>,
+    /// How the source file is encoded
+    pub encoding: Encoding,
+    /// Files of debug commands to run before the prompt
+    pub commands: Vec<String>,
+    /// Exit after the debug commands instead of showing the prompt
+    pub batch: bool,
+}
+
+/// How many instructions run between two checks of the clock
+const CLOCK_INTERVAL: u64 = 4096;
+
 /// A value that can be in the stack
-#[derive(Debug, Clone, Copy)]
-enum Operand {
+///
+/// The layout is fixed, so that compiled code can work on the stack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub enum Operand {
     Integer(i32),
     // Float(f32),
     Address(usize),
@@ -42,7 +74,15 @@ impl fmt::Display for Operand {
 }
 
 impl Operand {
-    fn add(n: Self, a: Self) -> Result<Self> {
+    /// The value as a `json` object
+    pub fn to_json(self) -> String {
+        match self {
+            Operand::Integer(i) => format!("{{\"type\":\"integer\",\"value\":{}}}", i),
+            Operand::Address(a) => format!("{{\"type\":\"address\",\"value\":{}}}", a),
+        }
+    }
+
+    pub fn add(n: Self, a: Self) -> Result<Self> {
         match (n, a) {
             (Operand::Integer(n), Operand::Address(a)) => {
                 Ok(Operand::Address((a as i32 + n) as usize))
@@ -55,7 +95,7 @@ impl Operand {
         }
     }
 
-    fn sub(n: Self, a: Self) -> Result<Self> {
+    pub fn sub(n: Self, a: Self) -> Result<Self> {
         match (n, a) {
             (Operand::Integer(n), Operand::Address(a)) => {
                 if n.is_negative() {
@@ -79,7 +119,7 @@ impl Operand {
         }
     }
 
-    fn mul(n: Self, m: Self) -> Result<Self> {
+    pub fn mul(n: Self, m: Self) -> Result<Self> {
         match (n, m) {
             (Operand::Integer(n), Operand::Integer(m)) => Ok(Operand::Integer(m * n)),
             _ => bail!(ErrorKind::IllegalOperand(format!(
@@ -89,7 +129,7 @@ impl Operand {
         }
     }
 
-    fn div(n: Self, m: Self) -> Result<Self> {
+    pub fn div(n: Self, m: Self) -> Result<Self> {
         match (n, m) {
             (Operand::Integer(0), _) => bail!(ErrorKind::DivisionByZero),
             (Operand::Integer(n), Operand::Integer(m)) => Ok(Operand::Integer(m / n)),
@@ -100,7 +140,7 @@ impl Operand {
         }
     }
 
-    fn module(n: Self, m: Self) -> Result<Self> {
+    pub fn module(n: Self, m: Self) -> Result<Self> {
         match (n, m) {
             (Operand::Integer(n), Operand::Integer(m)) => Ok(Operand::Integer(m % n)),
             _ => bail!(ErrorKind::IllegalOperand(format!(
@@ -110,7 +150,7 @@ impl Operand {
         }
     }
 
-    fn equal(n: Self, m: Self) -> Result<Self> {
+    pub fn equal(n: Self, m: Self) -> Result<Self> {
         match (n, m) {
             (Operand::Integer(n), Operand::Integer(m)) if n == m => Ok(Operand::Integer(1)),
             (Operand::Integer(..), Operand::Integer(..)) => Ok(Operand::Integer(0)),
@@ -121,7 +161,7 @@ impl Operand {
         }
     }
 
-    fn inf(n: Self, m: Self) -> Result<Self> {
+    pub fn inf(n: Self, m: Self) -> Result<Self> {
         match (n, m) {
             (Operand::Integer(n), Operand::Integer(m)) if m < n => Ok(Operand::Integer(1)),
             (Operand::Integer(..), Operand::Integer(..)) => Ok(Operand::Integer(0)),
@@ -132,7 +172,7 @@ impl Operand {
         }
     }
 
-    fn infeq(n: Self, m: Self) -> Result<Self> {
+    pub fn infeq(n: Self, m: Self) -> Result<Self> {
         match (n, m) {
             (Operand::Integer(n), Operand::Integer(m)) if m <= n => Ok(Operand::Integer(1)),
             (Operand::Integer(..), Operand::Integer(..)) => Ok(Operand::Integer(0)),
@@ -143,7 +183,7 @@ impl Operand {
         }
     }
 
-    fn sup(n: Self, m: Self) -> Result<Self> {
+    pub fn sup(n: Self, m: Self) -> Result<Self> {
         match (n, m) {
             (Operand::Integer(n), Operand::Integer(m)) if m > n => Ok(Operand::Integer(1)),
             (Operand::Integer(..), Operand::Integer(..)) => Ok(Operand::Integer(0)),
@@ -154,7 +194,7 @@ impl Operand {
         }
     }
 
-    fn supeq(n: Self, m: Self) -> Result<Self> {
+    pub fn supeq(n: Self, m: Self) -> Result<Self> {
         match (n, m) {
             (Operand::Integer(n), Operand::Integer(m)) if m >= n => Ok(Operand::Integer(1)),
             (Operand::Integer(..), Operand::Integer(..)) => Ok(Operand::Integer(0)),
@@ -166,57 +206,106 @@ impl Operand {
     }
 }
 
+/// A stack slot used by an instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read(usize),
+    Write(usize),
+}
+
 /// The Main struct responsible for the `vm`
 #[derive(Default, Clone)]
-struct Machine {
+pub struct Machine {
     /// Frame Pointer
-    fp: usize,
+    pub fp: usize,
     /// Program Counter
-    pc: usize,
+    pub pc: usize,
     /// Global Variables Base Address
-    gp: usize,
+    pub gp: usize,
     /// Operand Stack
-    stack: Vec<Operand>,
+    pub stack: Vec<Operand>,
     /// Call Stack (instruction address, frame pointer)
-    call_stack: Vec<(usize, usize)>,
+    pub call_stack: Vec<(usize, usize)>,
     /// Code
-    code: Vec<Instruction>,
+    pub code: Vec<Instruction>,
+    /// Code as run by the interpreter
+    ops: Vec<Op>,
+    /// Code with superinstructions, as run outside of the debugger
+    fast: Vec<Op>,
     /// String Stack
-    strings: Vec<String>,
+    pub strings: Vec<String>,
     /// Label Map
-    labels: HashMap<String, usize>,
+    pub labels: HashMap<String, usize>,
+    /// Source location of each instruction
+    pub spans: Vec<Span>,
+    /// Number of instructions executed
+    pub steps: u64,
+    /// Execution limits
+    pub options: Options,
+    /// Execution statistics, when requested
+    stats: Option<Stats>,
+    /// Cost of each instruction
+    costs: CostTable,
+    /// Cost of the instruction at each code address
+    op_costs: Vec<u64>,
+    /// Total cost of the instructions executed
+    pub cost: u64,
+    /// Don't print the output of the program, while replaying it
+    pub quiet: bool,
+    /// Input for the next `read`, instead of the standard input
+    pub input: Option<String>,
+    /// Native code compiled for the program
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
 }
 
 impl Machine {
-    fn new() -> Self {
-        Self::default()
+    pub fn new(options: Options) -> Self {
+        Machine {
+            stats: if options.stats {
+                Some(Stats::default())
+            } else {
+                None
+            },
+            costs: options.costs.clone().unwrap_or_default(),
+            options,
+            ..Self::default()
+        }
     }
 
-    fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        // Open file
-        let mut f = File::open(&path)
-            .chain_err(|| format!("Failed to open file '{}'", path.as_ref().display()))?;
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let program = Program::load(&path, self.options.encoding)?;
+        self.load_program(program)
+            .chain_err(|| format!("Unable to Parse file '{}'", path.as_ref().display()))
+    }
 
-        // Load file to memory
-        let mut buffer = String::new();
-        f.read_to_string(&mut buffer)
-            .chain_err(|| format!("Unable to Read file '{}'", path.as_ref().display()))?;
+    pub fn load_program(&mut self, program: Program) -> Result<()> {
+        let bytecode = bytecode::compile(&program)?;
 
-        // Parse the file
-        let (code, labels) = parser::parse(&buffer).chain_err(|| {
-            ErrorKind::Anomaly(format!(
-                "Unable to Parse file '{}'",
-                path.as_ref().display()
-            ))
-        })?;
+        self.op_costs = program.code.iter().map(|i| self.costs.get(i)).collect();
+        self.code = program.code;
+        self.labels = program.labels;
+        self.spans = program.spans;
+        self.fast = bytecode::fuse(&bytecode.ops);
+        self.ops = bytecode.ops;
 
-        self.code = code;
-        self.labels = labels;
+        // The string operands are the first strings of the string area
+        for s in bytecode.constants {
+            self.alloc_string(s);
+        }
 
         Ok(())
     }
 
-    fn sp(&self) -> usize {
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            span: self.spans.get(self.pc).cloned(),
+            stack: self.stack.clone(),
+        }
+    }
+
+    pub fn sp(&self) -> usize {
         self.stack.len()
     }
 
@@ -232,177 +321,257 @@ impl Machine {
             .ok_or_else(|| ErrorKind::SegmentationFault("Call stack is empty".to_string()).into())
     }
 
-    fn readline(&self) -> Result<Command> {
-        let mut buf = String::new();
-        io::stdin()
-            .read_line(&mut buf)
-            .chain_err(|| "Error reading line")?;
-        buf.parse()
+    /// Fail when the instruction budget has run out
+    pub fn check_steps(&self) -> Result<()> {
+        if let Some(max) = self.options.max_steps {
+            if self.steps >= max {
+                bail!(ErrorKind::BudgetExhausted(format!(
+                    "step limit of {} instructions reached",
+                    max
+                )));
+            }
+        }
+        Ok(())
     }
 
-    fn debug(&mut self, cmd: &Command, status: Status) -> Result<Status> {
-        match *cmd {
-            Command::PrintCode => {
-                println!("\t/// CODE ///");
-                for (i, line) in self.code.iter().enumerate() {
-                    for (k, _) in self.labels.iter().filter(|&(_, &v)| v == i) {
-                        println!("{}:", k);
-                    }
-                    println!("  {:>2}|\t{}", i, line);
-                }
-                Ok(status)
+    /// Fail when the time budget has run out
+    pub fn check_time(&self, started: Instant) -> Result<()> {
+        if let Some(timeout) = self.options.timeout {
+            if started.elapsed() >= timeout {
+                bail!(ErrorKind::BudgetExhausted(format!(
+                    "time limit of {} seconds reached",
+                    timeout.as_secs_f64()
+                )));
             }
-            Command::PrintLabels => {
-                println!("Labels:");
-                for (k, val) in &self.labels {
-                    println!(" | {} => {}", k, val);
-                }
-                Ok(status)
-            }
-            Command::PrintRegisters => {
-                print!("Registeres: ");
-                print!(" sp = {:2} |", self.sp());
-                print!(" fp = {:2} |", self.fp);
-                print!(" pc = {:2} |", self.pc);
-                print!(" gp = {:2} |", self.gp);
-                println!();
-                Ok(status)
-            }
-            Command::PrintStack => {
-                println!("Stack:");
-                print!("--- <- sp");
-                if self.fp == self.sp() {
-                    print!(" <- fp");
-                }
-                println!();
+        }
+        Ok(())
+    }
 
-                for (i, val) in self.stack.iter().enumerate().rev() {
-                    print!("{}", val);
-                    if self.fp == i {
-                        print!(" <- fp");
-                    }
-                    println!();
-                }
-                Ok(status)
+    fn run(&mut self) -> Result<()> {
+        // Statistics are kept by the interpreter alone
+        #[cfg(feature = "jit")]
+        {
+            if self.jit.is_none() && self.stats.is_none() {
+                self.jit = Some(Jit::new(&self.ops, &self.op_costs));
             }
-            Command::Run => Ok(match status {
-                Status::Success => {
-                    self.run()?;
-                    println!();
-                    Status::Exit
-                }
-                Status::Exit => status,
-            }),
-            Command::Next(end) => {
-                if let Status::Success = status {
-                    let mut bk = self.clone();
-                    for _ in 0..end {
-                        let instr = bk.get_instruction()?;
-                        println!("\t: {} :", instr);
-                        instr.write_ln();
-                        if let Status::Exit = bk.run_instruction(&instr)? {
-                            break;
-                        }
-                    }
-                }
-                Ok(status)
+        }
+
+        let started = Instant::now();
+        let mut next_check = self.steps;
+        loop {
+            if self.steps >= next_check {
+                self.check_time(started)?;
+                next_check = self.steps + CLOCK_INTERVAL;
             }
-            Command::Step(end) => {
-                if let Status::Success = status {
-                    for _ in 0..end {
-                        let instr = &self.get_instruction()?;
-                        println!("\t< {} >", instr);
-                        let s = self.run_instruction(instr)?;
-                        instr.write_ln();
-                        if let Status::Exit = s {
-                            return Ok(s);
-                        }
-                    }
+            self.check_steps()?;
+            #[cfg(feature = "jit")]
+            {
+                let limit = self
+                    .options
+                    .max_steps
+                    .map_or(next_check, |max| max.min(next_check));
+                if self.run_jit(limit) {
+                    continue;
                 }
-                Ok(status)
             }
-            Command::Help => {
-                Command::help();
-                Ok(status)
-            }
-            Command::Quit => {
-                ::std::process::exit(0);
+            if let Status::Exit = self.run_fused()? {
+                break;
             }
-            Command::Empty => Ok(status),
         }
+        Ok(())
     }
 
-    fn run_debug(&mut self) -> Result<()> {
-        let mut status = Status::Success;
-        loop {
-            match status {
-                Status::Success => print!("(debug) "),
-                Status::Exit => print!("(debug - finished) "),
-            }
-            io::stdout().flush().expect("Could not flush stdout");
+    /// Run compiled code from `pc` until at most `limit` instructions
+    /// have run in total. Returns whether any instruction was run.
+    #[cfg(feature = "jit")]
+    fn run_jit(&mut self, limit: u64) -> bool {
+        let jit = match self.jit {
+            Some(ref mut jit) => jit,
+            None => return false,
+        };
+        let mut regs = jit::Registers {
+            pc: self.pc,
+            gp: self.gp,
+            steps: self.steps,
+            cost: self.cost,
+        };
+        if !jit.run(&mut regs, &mut self.stack, limit) {
+            return false;
+        }
+        self.pc = regs.pc;
+        self.steps = regs.steps;
+        self.cost = regs.cost;
+        true
+    }
 
-            let cmd = self.readline().unwrap_or_else(|ref e| {
-                errors::print_errs(e);
-                Command::Empty
-            });
-            status = self.debug(&cmd, status).unwrap_or_else(|ref e| {
-                errors::print_errors(e);
-                Status::Exit
-            });
+    /// Execute the superinstruction at `pc`, or the instruction if there is none
+    fn run_fused(&mut self) -> Result<Status> {
+        let (pc, op) = match self.fast.get(self.pc) {
+            Some(&op) if op.width() > 1 => (self.pc, op),
+            _ => return self.run_instruction(),
+        };
+
+        let width = op.width();
+        let within_budget = self
+            .options
+            .max_steps
+            .is_none_or(|max| self.steps + width as u64 <= max);
+
+        let depth = self.stack.len();
+        // The instructions are run one by one when the shortcut could
+        // fail, so that errors are reported exactly as without it
+        if !within_budget || !self.execute_fused(op) {
+            return self.run_instruction();
+        }
+
+        self.steps += width as u64;
+        self.cost += self.op_costs[pc..pc + width].iter().sum::<u64>();
+        if let Some(ref mut stats) = self.stats {
+            for i in pc..pc + width {
+                stats.record(i);
+            }
+            stats.observe(depth + op.peak(), self.call_stack.len());
         }
+        Ok(Status::Success)
     }
 
-    fn run(&mut self) -> Result<()> {
-        loop {
-            let instr = self.get_instruction()?;
-            if let Status::Exit = self.run_instruction(&instr)? {
-                break;
+    /// Address of a global variable, as computed by `pushgp; pushi k; padd`
+    fn global(&self, k: i32) -> usize {
+        (self.gp as i32 + k) as usize
+    }
+
+    /// Execute a superinstruction, unless it would fail
+    fn execute_fused(&mut self, op: Op) -> bool {
+        let len = self.stack.len();
+        match op {
+            Op::GlobalAddr(k) => {
+                let addr = self.global(k);
+                self.push_reg(addr);
+            }
+            Op::LoadGlobal(k) => {
+                let addr = self.global(k);
+                if addr >= len {
+                    return false;
+                }
+                let v = self.stack[addr];
+                self.stack.push(v);
             }
+            Op::StoreGlobalI(k, v) => {
+                let addr = self.global(k);
+                if addr >= len {
+                    return false;
+                }
+                self.stack[addr] = Operand::Integer(v);
+            }
+            Op::StoreGlobalG(k, n) => {
+                let (addr, src) = (self.global(k), self.gp + n);
+                if addr >= len || src >= len {
+                    return false;
+                }
+                self.stack[addr] = self.stack[src];
+            }
+            _ => return false,
         }
-        Ok(())
+        self.pc += op.width();
+        true
     }
 
-    fn run_instruction(&mut self, inst: &Instruction) -> Result<Status> {
-        match *inst {
-            Instruction::Pushi(val) => self.pushi(val),
-            Instruction::Pushn(val) => self.pushn(val),
-            Instruction::Pushg(val) => self.pushg(val),
-            Instruction::Pushs(ref val) => self.pushs(val),
-            Instruction::Pusha(ref val) => self.pusha(val),
-            Instruction::Pushgp => self.pushgp(),
-            Instruction::Call => self.call()?,
-            Instruction::Return => self.ret()?,
-            Instruction::Start => self.start(),
-            Instruction::Nop => {}
-            Instruction::Stop => return Ok(Status::Exit),
-            Instruction::Loadn => self.loadn()?,
-            Instruction::Writei => self.writei()?,
-            Instruction::Writes => self.writes()?,
-            Instruction::Read => self.read(),
-            Instruction::Atoi => self.atoi()?,
-            Instruction::Padd => self.padd()?,
-            Instruction::Add => self.add()?,
-            Instruction::Sub => self.sub()?,
-            Instruction::Mul => self.mul()?,
-            Instruction::Div => self.div()?,
-            Instruction::Mod => self.module()?,
-            Instruction::Storeg(val) => self.storeg(val)?,
-            Instruction::Storen => self.storen()?,
-            Instruction::Equal => self.equal()?,
-            Instruction::Inf => self.inf()?,
-            Instruction::Infeq => self.infeq()?,
-            Instruction::Sup => self.sup()?,
-            Instruction::Supeq => self.supeq()?,
-            Instruction::Jump(ref val) => self.jump(val),
-            Instruction::Jz(ref val) => self.jz(val)?,
-            Instruction::Err(ref err) => bail!(ErrorKind::Error(err.to_string())),
+    /// Execute the instruction at `pc`
+    pub fn run_instruction(&mut self) -> Result<Status> {
+        let op = *self
+            .ops
+            .get(self.pc)
+            .ok_or_else(|| ErrorKind::SegmentationFault("Invalid PC counter".to_string()))?;
+
+        self.steps += 1;
+        self.cost += self.op_costs[self.pc];
+        if let Some(ref mut stats) = self.stats {
+            stats.record(self.pc);
+        }
+        let status = self.execute(op);
+        if let Some(ref mut stats) = self.stats {
+            stats.observe(self.stack.len(), self.call_stack.len());
+        }
+        status
+    }
+
+    fn execute(&mut self, op: Op) -> Result<Status> {
+        match op {
+            Op::Pushi(val) => self.pushi(val),
+            Op::Pushn(val) => self.pushn(val),
+            Op::Pushg(val) => self.pushg(val),
+            Op::Pushs(val) => self.pushs(val),
+            Op::Pusha(val) => self.pusha(val),
+            Op::Pushgp => self.pushgp(),
+            Op::Call => {
+                self.call()?;
+                return Ok(Status::Success);
+            }
+            Op::Return => self.ret()?,
+            Op::Start => self.start(),
+            Op::Nop => {}
+            Op::Stop => return Ok(Status::Exit),
+            Op::Loadn => self.loadn()?,
+            Op::Writei => self.writei()?,
+            Op::Writes => self.writes()?,
+            Op::Read => self.read(),
+            Op::Atoi => self.atoi()?,
+            Op::Padd => self.padd()?,
+            Op::Add => self.add()?,
+            Op::Sub => self.sub()?,
+            Op::Mul => self.mul()?,
+            Op::Div => self.div()?,
+            Op::Mod => self.module()?,
+            Op::Storeg(val) => self.storeg(val)?,
+            Op::Storen => self.storen()?,
+            Op::Equal => self.equal()?,
+            Op::Inf => self.inf()?,
+            Op::Infeq => self.infeq()?,
+            Op::Sup => self.sup()?,
+            Op::Supeq => self.supeq()?,
+            Op::Jump(addr) => {
+                self.jump(addr);
+                return Ok(Status::Success);
+            }
+            Op::Jz(addr) => {
+                if self.jz(addr)? {
+                    return Ok(Status::Success);
+                }
+            }
+            Op::Err(val) => bail!(ErrorKind::Error(self.strings[val].clone())),
+            Op::GlobalAddr(..) | Op::LoadGlobal(..) | Op::StoreGlobalI(..) | Op::StoreGlobalG(..) => {
+                bail!(ErrorKind::Anomaly(format!("{:?} is not a single instruction", op)))
+            }
         }
         self.pc += 1;
 
         Ok(Status::Success)
     }
 
-    fn get_instruction(&self) -> Result<Instruction> {
+    /// The stack slot the instruction at `pc` reads or writes, other than
+    /// the top of the stack. Instructions that store into slots or load
+    /// from them must be listed here for watchpoints to see them.
+    pub fn access(&self) -> Option<Access> {
+        let top = |i: usize| self.sp().checked_sub(i).map(|i| self.stack[i]);
+        let indexed = |a, n| match Operand::add(n, a) {
+            Ok(Operand::Address(addr)) => Some(addr),
+            _ => None,
+        };
+        match *self.ops.get(self.pc)? {
+            Op::Pushg(n) => Some(Access::Read(self.gp + n)),
+            Op::Storeg(n) => Some(Access::Write(self.gp + n)),
+            Op::Loadn => indexed(top(2)?, top(1)?).map(Access::Read),
+            Op::Storen => indexed(top(3)?, top(2)?).map(Access::Write),
+            _ => None,
+        }
+    }
+
+    /// Number of values the instruction at `pc` takes off the stack
+    pub fn pops(&self) -> usize {
+        self.ops.get(self.pc).map_or(0, |op| op.pops())
+    }
+
+    pub fn get_instruction(&self) -> Result<Instruction> {
         self.code
             .get(self.pc)
             .cloned()
@@ -435,17 +604,21 @@ impl Machine {
         self.stack.push(value);
     }
 
-    fn pushs(&mut self, val: &str) {
-        if let Some(i) = self.strings.iter().position(|x| x == val) {
-            self.stack.push(Operand::Address(i));
-        } else {
-            self.strings.push(val.to_string());
-            self.stack.push(Operand::Address(self.strings.len() - 1));
+    /// Add a string to the string area and return its address
+    fn alloc_string(&mut self, val: String) -> usize {
+        if let Some(ref mut stats) = self.stats {
+            stats.alloc(val.len());
         }
+        self.strings.push(val);
+        self.strings.len() - 1
+    }
+
+    fn pushs(&mut self, val: usize) {
+        // constants are loaded at the start of the string area
+        self.stack.push(Operand::Address(val));
     }
 
-    fn pusha(&mut self, val: &str) {
-        let addr = self.labels[val] - 1;
+    fn pusha(&mut self, addr: usize) {
         self.stack.push(Operand::Address(addr));
     }
 
@@ -468,8 +641,10 @@ impl Machine {
     fn writei(&mut self) -> Result<()> {
         let val = self.stack_pop()?;
         if let Operand::Integer(i) = val {
-            print!("{}", i);
-            io::stdout().flush().expect("Could not flush stdout");
+            if !self.quiet {
+                print!("{}", i);
+                io::stdout().flush().expect("Could not flush stdout");
+            }
         } else {
             bail!(ErrorKind::IllegalOperand(
                 "writei: Not an Integer".to_string()
@@ -480,6 +655,7 @@ impl Machine {
 
     fn writes(&mut self) -> Result<()> {
         match self.stack_pop()? {
+            Operand::Address(_) if self.quiet => {}
             Operand::Address(addr) => {
                 print!("{}", self.strings[addr]);
                 io::stdout().flush().expect("Could not flush stdout");
@@ -491,27 +667,35 @@ impl Machine {
         Ok(())
     }
 
-    fn read(&mut self) -> () {
-        let mut input = String::new();
-
-        io::stdin()
-            .read_line(&mut input)
-            .expect("Failed to read line from stdin");
+    fn read(&mut self) {
+        let input = match self.input.take() {
+            Some(input) => input,
+            None => {
+                let mut input = String::new();
+                io::stdin()
+                    .read_line(&mut input)
+                    .expect("Failed to read line from stdin");
+                input
+            }
+        };
 
-        self.strings.push(input.trim().to_string());
-        self.stack.push(Operand::Address(self.strings.len() - 1));
+        let addr = self.alloc_string(input.trim().to_string());
+        self.stack.push(Operand::Address(addr));
     }
 
     fn atoi(&mut self) -> Result<()> {
         let adr = match self.stack_pop()? {
-            Operand::Address(addr) => self.strings.remove(addr),
+            Operand::Address(addr) => self.strings[addr].clone(),
             _ => bail!(ErrorKind::IllegalOperand(
                 "atoi => Must be an address to write string".to_string()
             )),
         };
 
         match adr.parse() {
-            Ok(val) => Ok(self.pushi(val)),
+            Ok(val) => {
+                self.pushi(val);
+                Ok(())
+            }
             Err(_) => bail!(ErrorKind::IllegalOperand(
                 "Value is not a valid Integer".to_string()
             )),
@@ -536,6 +720,7 @@ impl Machine {
         Ok(())
     }
 
+    /// Jump to the code address on top of the stack
     fn call(&mut self) -> Result<()> {
         match self.stack_pop()? {
             Operand::Address(addr) => {
@@ -613,35 +798,121 @@ impl Machine {
         self.binary_op(Operand::supeq)
     }
 
-    fn jump(&mut self, val: &str) {
-        self.pc = self.labels[val] - 1;
+    fn jump(&mut self, addr: usize) {
+        self.pc = addr;
     }
 
-    fn jz(&mut self, val: &str) -> Result<()> {
+    /// Returns whether the jump was taken
+    fn jz(&mut self, addr: usize) -> Result<bool> {
         let eq = self.stack_pop()?;
 
         match eq {
-            Operand::Integer(0) => self.jump(val),
-            Operand::Integer(1) => {}
+            Operand::Integer(0) => self.jump(addr),
+            Operand::Integer(1) => return Ok(false),
             _ => bail!(ErrorKind::IllegalOperand(
                 "jz => Not an Integer(0|1)".to_string()
             )),
         }
-        Ok(())
+        Ok(true)
     }
 }
 
-/// `vm` entry point
-pub fn start<P: AsRef<Path>>(path: P, mode: Mode) -> Result<()> {
-    let mut m = Machine::new();
+/// `vm` entry point, returning the exit code of a run that did not fail
+pub fn start<P: AsRef<Path>>(
+    path: P,
+    mode: Mode,
+    options: &Options,
+) -> ::std::result::Result<i32, Failure> {
+    let mut m = Machine::new(options.clone());
     m.load(&path)?;
     // println!("{:#?}", m);
 
-    match mode {
-        Mode::Running => m.run()?,
-        Mode::Debug => m.run_debug()?,
+    let started = Instant::now();
+    let res = match mode {
+        Mode::Running => m.run().map(|_| 0),
+        Mode::Debug => Debugger::new(&mut m, path.as_ref()).run(),
+    };
+
+    if let Some(ref mut stats) = m.stats {
+        stats.elapsed = started.elapsed();
+        stats.cost = m.cost;
+        stats.print(&m.code);
+    } else if m.options.print_cost {
+        eprintln!("\ntotal cost: {}", m.cost);
     }
 
-    println!();
-    Ok(())
+    match res {
+        Ok(code) => {
+            // the debugger ends its own lines
+            if let Mode::Running = mode {
+                println!();
+            }
+            Ok(code)
+        }
+        Err(e) => Err(Failure {
+            error: e,
+            snapshot: Some(Box::new(m.snapshot())),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Machine, Operand, Options};
+    use errors::{self, ErrorKind};
+    use parser::parse;
+
+    /// A machine running `nop` and `jump` forever
+    fn forever(options: Options) -> Machine {
+        let mut m = Machine::new(options);
+        m.load_program(parse("start\nloop: nop\njump loop\n").unwrap()).unwrap();
+        m
+    }
+
+    #[test]
+    fn step_budget() {
+        let mut m = forever(Options { max_steps: Some(100), ..Options::default() });
+        let e = m.run().unwrap_err();
+        match *e.kind() {
+            ErrorKind::BudgetExhausted(ref s) => assert_eq!(s, "step limit of 100 instructions reached"),
+            ref kind => panic!("{:?}", kind),
+        }
+        assert_eq!(errors::exit_code(&e), 124);
+        assert_eq!((m.steps, m.pc), (100, 2));
+    }
+
+    #[test]
+    fn time_budget() {
+        let mut m = forever(Options { timeout: Some(Duration::from_millis(20)), ..Options::default() });
+        let e = m.run().unwrap_err();
+        assert!(matches!(*e.kind(), ErrorKind::BudgetExhausted(_)));
+        assert_eq!(errors::exit_code(&e), 124);
+    }
+
+    #[test]
+    fn stats() {
+        let mut m = Machine::new(Options { stats: true, ..Options::default() });
+        m.load_program(parse("start\npusha f\ncall\nstop\nf: pushs \"ab\"\nreturn").unwrap()).unwrap();
+        m.run().unwrap();
+        let stats = m.stats.clone().unwrap();
+        assert_eq!(stats.steps, m.steps);
+        assert_eq!(stats.counts, [1, 1, 1, 1, 1, 1]);
+        assert_eq!((stats.max_stack, stats.max_calls, stats.strings), (1, 1, 1));
+    }
+
+    #[test]
+    fn string_area() {
+        let mut m = Machine::new(Options::default());
+        let code = "start\nread\natoi\npushs \"12\"\npusha f\npushs \"x\"\nstop\nf: return\n";
+        m.load_program(parse(code).unwrap()).unwrap();
+        m.input = Some("12\n".to_string());
+        m.run().unwrap();
+        // the constants come first, and `atoi` keeps the string it read
+        assert_eq!(m.strings, ["12", "x", "12"]);
+        // `pushs` uses its constant even when an equal string was read
+        let stack = [Operand::Integer(12), Operand::Address(0), Operand::Address(7), Operand::Address(1)];
+        assert_eq!(m.stack, stack);
+    }
 }
@@ -0,0 +1,365 @@
+//! Binary program files (`.vmb`)
+//!
+//! All numbers are little endian. A file is made of:
+//!
+//! | Section    | Contents                                                   |
+//! |------------|------------------------------------------------------------|
+//! | header     | magic `PLVM`, version `u16`, flags `u16`                   |
+//! | sizes      | number of constants, labels and instructions as `u32`      |
+//! | constants  | each string as a `u32` length followed by `utf-8` bytes    |
+//! | labels     | each name as a constant index `u32`, then its address `u32`|
+//! | code       | each instruction as an opcode `u8` and its operand         |
+//! | source map | when flagged, line, col, end line and end col as `u32`     |
+//!
+//! Operands are an `i32` for `pushi` and `pushn`, a `u32` for `pushg` and
+//! `storeg`, a constant index for `pushs` and `err`, and a label index for
+//! `jump`, `jz` and `pusha`.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+
+use errors::*;
+use instructions::Instruction;
+use program::{Program, Span};
+
+/// The first bytes of every `.vmb` file
+pub const MAGIC: &[u8; 4] = b"PLVM";
+/// The version of the format written by this `vm`
+pub const VERSION: u16 = 1;
+
+/// The file has a source map section
+const FLAG_SOURCE_MAP: u16 = 1;
+
+/// Whether the contents of a file are a `.vmb` program
+pub fn is_vmb(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Builds the constant and label tables while encoding
+#[derive(Default)]
+struct Tables {
+    constants: Vec<String>,
+    index: HashMap<String, u32>,
+}
+
+impl Tables {
+    fn constant(&mut self, s: &str) -> u32 {
+        if let Some(&i) = self.index.get(s) {
+            return i;
+        }
+        let i = self.constants.len() as u32;
+        self.constants.push(s.to_string());
+        self.index.insert(s.to_string(), i);
+        i
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, n: u16) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+/// Encode a program, with its source map when `source_map` is set
+pub fn encode(program: &Program, source_map: bool) -> Result<Vec<u8>> {
+    let mut tables = Tables::default();
+
+    // Sort the labels so that the output does not depend on the map order
+    let mut labels: Vec<(&String, &usize)> = program.labels.iter().collect();
+    labels.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+    let label_index: HashMap<&str, u32> = labels
+        .iter()
+        .enumerate()
+        .map(|(i, &(name, _))| (name.as_str(), i as u32))
+        .collect();
+    let label = |name: &str| -> Result<u32> {
+        label_index
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Undefined label '{}'", name).into())
+    };
+    let global = |index: usize| -> Result<u32> {
+        u32::try_from(index).chain_err(|| format!("Global {} does not fit in a u32", index))
+    };
+
+    let mut code = Vec::new();
+    for instr in &program.code {
+        let (opcode, operand) = match *instr {
+            Instruction::Pushi(val) => (0, Some(val as u32)),
+            Instruction::Pushn(val) => (1, Some(val as u32)),
+            Instruction::Pushg(val) => (2, Some(global(val)?)),
+            Instruction::Pushs(ref val) => (3, Some(tables.constant(val))),
+            Instruction::Pusha(ref val) => (4, Some(label(val)?)),
+            Instruction::Pushgp => (5, None),
+            Instruction::Call => (6, None),
+            Instruction::Return => (7, None),
+            Instruction::Start => (8, None),
+            Instruction::Nop => (9, None),
+            Instruction::Stop => (10, None),
+            Instruction::Loadn => (11, None),
+            Instruction::Writei => (12, None),
+            Instruction::Writes => (13, None),
+            Instruction::Read => (14, None),
+            Instruction::Atoi => (15, None),
+            Instruction::Padd => (16, None),
+            Instruction::Add => (17, None),
+            Instruction::Sub => (18, None),
+            Instruction::Mul => (19, None),
+            Instruction::Div => (20, None),
+            Instruction::Mod => (21, None),
+            Instruction::Storeg(val) => (22, Some(global(val)?)),
+            Instruction::Storen => (23, None),
+            Instruction::Equal => (24, None),
+            Instruction::Inf => (25, None),
+            Instruction::Infeq => (26, None),
+            Instruction::Sup => (27, None),
+            Instruction::Supeq => (28, None),
+            Instruction::Jump(ref val) => (29, Some(label(val)?)),
+            Instruction::Jz(ref val) => (30, Some(label(val)?)),
+            Instruction::Err(ref val) => (31, Some(tables.constant(val))),
+        };
+        code.push(opcode);
+        if let Some(n) = operand {
+            put_u32(&mut code, n);
+        }
+    }
+
+    let label_names: Vec<u32> = labels.iter().map(|&(name, _)| tables.constant(name)).collect();
+
+    let with_map = source_map && program.spans.len() == program.code.len();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    put_u16(&mut out, VERSION);
+    put_u16(&mut out, if with_map { FLAG_SOURCE_MAP } else { 0 });
+    put_u32(&mut out, tables.constants.len() as u32);
+    put_u32(&mut out, labels.len() as u32);
+    put_u32(&mut out, program.code.len() as u32);
+
+    for s in &tables.constants {
+        put_u32(&mut out, s.len() as u32);
+        out.extend_from_slice(s.as_bytes());
+    }
+    for (&(_, &addr), &name) in labels.iter().zip(&label_names) {
+        put_u32(&mut out, name);
+        put_u32(&mut out, addr as u32);
+    }
+    out.extend_from_slice(&code);
+    if with_map {
+        for span in &program.spans {
+            put_u32(&mut out, span.line as u32);
+            put_u32(&mut out, span.col as u32);
+            put_u32(&mut out, span.end_line as u32);
+            put_u32(&mut out, span.end_col as u32);
+        }
+    }
+
+    Ok(out)
+}
+
+/// Write a program to a `.vmb` file
+pub fn write<W: Write>(program: &Program, source_map: bool, mut out: W) -> Result<()> {
+    let bytes = encode(program, source_map)?;
+    out.write_all(&bytes).chain_err(|| "Unable to write program")
+}
+
+/// Reads the sections of a `.vmb` file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            bail!("Truncated file at byte {}", self.pos);
+        }
+        let res = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(res)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        self.u32().map(|n| n as usize)
+    }
+}
+
+/// Decode the contents of a `.vmb` file
+pub fn decode(bytes: &[u8]) -> Result<Program> {
+    let mut r = Reader { bytes, pos: 0 };
+
+    if r.take(MAGIC.len())? != MAGIC {
+        bail!("Not a .vmb file");
+    }
+    let version = r.u16()?;
+    if version != VERSION {
+        bail!(
+            "Unsupported .vmb version {}, expected version {}",
+            version,
+            VERSION
+        );
+    }
+    let flags = r.u16()?;
+
+    let n_constants = r.usize()?;
+    let n_labels = r.usize()?;
+    let n_code = r.usize()?;
+
+    let mut constants = Vec::new();
+    for _ in 0..n_constants {
+        let len = r.usize()?;
+        let s = ::std::str::from_utf8(r.take(len)?).chain_err(|| "Invalid string constant")?;
+        constants.push(s.to_string());
+    }
+    let constant = |i: usize| -> Result<String> {
+        constants
+            .get(i)
+            .cloned()
+            .ok_or_else(|| format!("Invalid constant index {}", i).into())
+    };
+
+    let mut names = Vec::new();
+    let mut labels = HashMap::new();
+    for _ in 0..n_labels {
+        let name = constant(r.usize()?)?;
+        let addr = r.usize()?;
+        if addr > n_code {
+            bail!("Label '{}' points outside of the code", name);
+        }
+        labels.insert(name.clone(), addr);
+        names.push(name);
+    }
+    let label = |i: usize| -> Result<String> {
+        names
+            .get(i)
+            .cloned()
+            .ok_or_else(|| format!("Invalid label index {}", i).into())
+    };
+
+    let mut code = Vec::new();
+    for _ in 0..n_code {
+        code.push(match r.u8()? {
+            0 => Instruction::Pushi(r.u32()? as i32),
+            1 => Instruction::Pushn(r.u32()? as i32),
+            2 => Instruction::Pushg(r.usize()?),
+            3 => Instruction::Pushs(constant(r.usize()?)?),
+            4 => Instruction::Pusha(label(r.usize()?)?),
+            5 => Instruction::Pushgp,
+            6 => Instruction::Call,
+            7 => Instruction::Return,
+            8 => Instruction::Start,
+            9 => Instruction::Nop,
+            10 => Instruction::Stop,
+            11 => Instruction::Loadn,
+            12 => Instruction::Writei,
+            13 => Instruction::Writes,
+            14 => Instruction::Read,
+            15 => Instruction::Atoi,
+            16 => Instruction::Padd,
+            17 => Instruction::Add,
+            18 => Instruction::Sub,
+            19 => Instruction::Mul,
+            20 => Instruction::Div,
+            21 => Instruction::Mod,
+            22 => Instruction::Storeg(r.usize()?),
+            23 => Instruction::Storen,
+            24 => Instruction::Equal,
+            25 => Instruction::Inf,
+            26 => Instruction::Infeq,
+            27 => Instruction::Sup,
+            28 => Instruction::Supeq,
+            29 => Instruction::Jump(label(r.usize()?)?),
+            30 => Instruction::Jz(label(r.usize()?)?),
+            31 => Instruction::Err(constant(r.usize()?)?),
+            op => bail!("Invalid opcode {} at byte {}", op, r.pos - 1),
+        });
+    }
+
+    let mut spans = Vec::new();
+    if flags & FLAG_SOURCE_MAP != 0 {
+        for _ in 0..n_code {
+            spans.push(Span {
+                line: r.usize()?,
+                col: r.usize()?,
+                end_line: r.usize()?,
+                end_col: r.usize()?,
+            });
+        }
+    }
+
+    if r.pos != bytes.len() {
+        bail!("Unexpected data at byte {}", r.pos);
+    }
+
+    Ok(Program {
+        code,
+        labels,
+        spans,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+    use instructions::Instruction;
+    use parser::parse;
+
+    const SOURCE: &str = include_str!("../vms/funcao.vm");
+
+    #[test]
+    fn round_trip() {
+        let program = parse(SOURCE).unwrap();
+        let decoded = decode(&encode(&program, true).unwrap()).unwrap();
+        assert_eq!(decoded.code, program.code);
+        assert_eq!(decoded.labels, program.labels);
+        assert_eq!(decoded.spans, program.spans);
+    }
+
+    #[test]
+    fn round_trip_stripped() {
+        let program = parse(SOURCE).unwrap();
+        let decoded = decode(&encode(&program, false).unwrap()).unwrap();
+        assert_eq!(decoded.code, program.code);
+        assert!(decoded.spans.is_empty());
+    }
+
+    #[test]
+    fn disassemble() {
+        let program = parse(SOURCE).unwrap();
+        let decoded = decode(&encode(&program, false).unwrap()).unwrap();
+        let reparsed = parse(&decoded.to_string()).unwrap();
+        assert_eq!(reparsed.code, program.code);
+        assert_eq!(reparsed.labels, program.labels);
+    }
+
+    #[test]
+    fn truncated() {
+        let program = parse(SOURCE).unwrap();
+        let bytes = encode(&program, true).unwrap();
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn large_global() {
+        let mut program = parse("pushg 0").unwrap();
+        program.code[0] = Instruction::Pushg(u32::MAX as usize + 1);
+        assert!(encode(&program, false).is_err());
+    }
+}